@@ -11,6 +11,8 @@
                  [jepsen "0.2.4-SNAPSHOT"]
                  [amalloy/ring-buffer "1.3.1"]
                  [cheshire "5.7.0"]
+                 ; Binary message encoding
+                 [clojure-msgpack "1.2.1"]
                  [byte-streams "0.2.2"]
                  ; Reductions over journals
                  [tesser.core "1.0.4"]
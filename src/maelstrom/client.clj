@@ -137,7 +137,19 @@
     (when-not ok?
       (throw (IllegalStateException.
                "Can't send more than one message at a time!")))
-    (net/send! net msg)))
+    (try+ (net/send! net msg)
+          (catch [:type ::net/node-not-found] e
+            ; There's nobody there, so we'll never hear back, and the request
+            ; definitely didn't happen.
+            (reset! (:waiting-for client) nil)
+            (throw+ {:type      :rpc-error
+                     :code      (:code node-not-found)
+                     :name      :node-not-found
+                     :definite? true
+                     :body      {:type "error"
+                                 :code (:code node-not-found)
+                                 :text (str "node " (:dest msg)
+                                            " is not in the network")}})))))
 
 (defn recv!
   "Receives a message for the given client. Times out after timeout ms."
@@ -0,0 +1,79 @@
+(ns maelstrom.codec
+  "Encodings for messages exchanged with node processes. JSON messages are
+  newline-delimited, as described in doc/protocol.md. Binary codecs (CBOR and
+  MessagePack) are framed instead: each message is a 4-byte, big-endian
+  length, followed by that many bytes of encoded message.
+
+  Decoded messages have string keys, just like parsed JSON, so the process
+  layer can coerce and validate them the same way regardless of codec."
+  (:require [cheshire.core :as json]
+            [clojure.walk :as walk]
+            [msgpack.core :as msgpack])
+  (:import (java.io DataInputStream
+                    DataOutputStream
+                    EOFException)))
+
+(def codecs
+  "The codecs we support."
+  #{:json :cbor :msgpack})
+
+(def max-frame-size
+  "The largest frame we'll read, in bytes. Anything bigger is probably a node
+  writing something other than a frame."
+  (* 64 1024 1024))
+
+(defn plain
+  "MessagePack doesn't know about keywords, sets, or records. Converts a
+  message into plain maps, vectors, and strings, the same way our JSON encoder
+  would see it."
+  [x]
+  (walk/postwalk (fn [x]
+                   (cond (keyword? x)    (subs (str x) 1)
+                         (record? x)     (into {} x)
+                         (map? x)        x
+                         (coll? x)       (vec x)
+                         :else           x))
+                 x))
+
+(defn encode
+  "Encodes a message as bytes, using the given binary codec."
+  ^bytes [codec message]
+  (case codec
+    :cbor    (json/generate-cbor message)
+    :msgpack (msgpack/pack (plain message))))
+
+(defn decode
+  "Decodes bytes into a message with string keys, using the given binary
+  codec."
+  [codec ^bytes bs]
+  (case codec
+    :cbor    (json/parse-cbor bs)
+    :msgpack (msgpack/unpack bs)))
+
+(defn read-frame!
+  "Reads a single length-prefixed frame from a stream, returning its bytes.
+  Returns nil at the end of the stream. Throws EOFException if the stream ends
+  partway through a frame, and IllegalStateException if the length makes no
+  sense: usually because the stream isn't made of frames at all."
+  ^bytes [^DataInputStream in]
+  (let [size (try (.readInt in)
+                  (catch EOFException e
+                    nil))]
+    (when size
+      (when-not (< -1 size max-frame-size)
+        (throw (IllegalStateException.
+                 (str "Invalid frame length " size))))
+      (let [bs (byte-array size)]
+        (try (.readFully in bs)
+             (catch EOFException e
+               (throw (EOFException.
+                        (str "Stream ended partway through a frame of " size
+                             " bytes")))))
+        bs))))
+
+(defn write-frame!
+  "Writes bytes to a stream as a single length-prefixed frame, and flushes."
+  [^DataOutputStream out ^bytes bs]
+  (.writeInt out (alength bs))
+  (.write out bs)
+  (.flush out))
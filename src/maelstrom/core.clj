@@ -1,16 +1,24 @@
 (ns maelstrom.core
   (:gen-class)
   (:refer-clojure :exclude [run! test])
-  (:require [clojure.string :as str]
+  (:require [clojure.edn :as edn]
+            [clojure.string :as str]
             [clojure.tools.logging :refer [info warn]]
             [elle.consistency-model :as cm]
             [maelstrom [client :as c]
+                       [codec :as codec]
                        [db :as db]
                        [doc :as doc]
+                       [generator :as mgen]
+                       [membership :as membership]
                        [net :as net]
                        [nemesis :as nemesis]
-                       [process :as process]]
-            [maelstrom.net.checker :as net.checker]
+                       [process :as process]
+                       [random :as r]
+                       [service :as service]]
+            [maelstrom.net [checker :as net.checker]
+                           [crash :as crash]
+                           [rules :as rules]]
             [maelstrom.workload [broadcast :as broadcast]
                                 [echo :as echo]
                                 [g-set :as g-set]
@@ -25,7 +33,8 @@
                     [store :as store]
                     [tests :as tests]
                     [util :as util :refer [timeout parse-long]]]
-            [jepsen.checker.timeline :as timeline]))
+            [jepsen.checker.timeline :as timeline]
+            [schema.core :as s]))
 
 (def workloads
   "A map of workload names to functions which construct workload maps."
@@ -39,24 +48,78 @@
 
 (def nemeses
   "A set of valid nemeses you can pass at the CLI."
-  #{:partition})
+  #{:partition :duplicate :slow :flaky :corrupt :equivocate :kill :pause
+    :slow-node :amnesia :clock :upgrade :membership})
 
 (defn maelstrom-test
   "Construct a Jepsen test from parsed CLI options"
   [{:keys [bin args nodes rate] :as opts}]
-  (let [nodes (:nodes opts)
-        net   (net/net (:latency opts)
-                       (:log-net-send opts)
-                       (:log-net-recv opts))
-        db            (db/db {:net net, :bin bin, :args args})
+  (let [seed  (r/seed! (or (:seed opts) (.nextLong (java.util.Random.))))
+        _     (info (str "Random seed is " seed "; pass --seed " seed
+                         " to reuse it"))
+        nodes (:nodes opts)
+        net   (net/net {:latency       (:latency opts)
+                        :client-latency (:client-latency opts)
+                        :log-send?     (:log-net-send opts)
+                        :log-recv?     (:log-net-recv opts)
+                        :virtual-time? (:virtual-time opts)
+                        :regions       (:regions opts)
+                        :region-latencies (:region-latencies opts)
+                        :p-duplicate   (:p-duplicate opts)
+                        :ordering      (:net-ordering opts)
+                        :bandwidth     (when-let [rate (:bandwidth opts)]
+                                         {:rate  rate
+                                          :unit  (:bandwidth-unit opts)
+                                          :scope (:bandwidth-scope opts)})
+                        :inbox-capacity (:inbox-capacity opts)
+                        :rules         (:net-rules opts)
+                        :crash-points  (:crash-points opts)})
+        ; The time service is shared with the clock nemesis, which skews it,
+        ; and the lock service, which reports lease expiry by nodes' clocks.
+        ; Both follow the network's clock, so they work in virtual time too.
+        clock         (service/clock #(net/now-millis @net))
+        db            (db/db {:net       net
+                               :bin       bin
+                               :args      args
+                               :transport (:transport opts)
+                               :codec     (:codec opts)
+                               :clock     clock})
         workload-name (:workload opts)
         workload      ((workloads workload-name)
                        (assoc opts :nodes nodes, :net net))
         nemesis-package (nemesis/package {:db       db
+                                          :nodes    nodes
                                           :interval (:nemesis-interval opts)
-                                          :faults   (:nemesis opts)})
+                                          :faults   (:nemesis opts)
+                                          :partition {:shapes
+                                                      (:nemesis-partitions
+                                                        opts)}
+                                          :duplicate {:p (:nemesis-duplicate-p
+                                                           opts)}
+                                          :slow {:factor (:nemesis-slow-factor
+                                                           opts)}
+                                          :flaky {:p (:nemesis-flaky-p opts)}
+                                          :slow-node {:duty
+                                                      (:nemesis-slow-node-duty
+                                                        opts)}
+                                          :corrupt {:p (:nemesis-corrupt-p
+                                                         opts)}
+                                          :equivocate
+                                          {:count (:nemesis-equivocate-count
+                                                    opts)}
+                                          :clock {:clock clock
+                                                  :max-skew
+                                                  (:nemesis-clock-skew
+                                                    opts)}
+                                          :upgrade {:bin (:upgrade-bin
+                                                           opts)}
+                                          :membership
+                                          {:net    net
+                                           :spares (membership/spare-nodes
+                                                     nodes
+                                                     (:spare-nodes opts))}})
         generator (->> (if (pos? rate)
-                         (gen/stagger (/ rate) (:generator workload))
+                         (mgen/stagger (/ rate) (:generator workload))
                          (gen/sleep (:time-limit opts)))
                        (gen/nemesis (:generator nemesis-package))
                        (gen/time-limit (:time-limit opts)))
@@ -73,12 +136,13 @@
            opts
            workload
            {:name    (str (name workload-name))
+            :seed    seed
             :nodes   nodes
             :ssh     {:dummy? true}
             :os      (net/jepsen-os net)
             :net     (net/jepsen-net net)
             :db      db
-            :nemesis (:nemesis nemesis-package)
+            :nemesis (nemesis/seeded (:nemesis nemesis-package))
             :checker (checker/compose
                        {:perf       (checker/perf
                                       {:nemeses (:perf nemesis-package)})
@@ -87,7 +151,7 @@
                         :stats      (checker/stats)
                         :net        (net.checker/checker)
                         :workload   (:checker workload)})
-            :generator generator
+            :generator (mgen/seeded :generator generator)
             :pure-generators true})))
 
 (def demos
@@ -110,10 +174,37 @@
   (for [demo demos]
     (maelstrom-test (merge opts demo))))
 
+(defn load-latency-trace
+  "Reads a file of round-trip times, in ms, one per line, and returns a vector
+  of one-way latencies: half of each RTT. Blank lines and lines starting with
+  # are ignored."
+  [file]
+  (->> (slurp file)
+       str/split-lines
+       (map str/trim)
+       (remove (fn [line] (or (= "" line) (str/starts-with? line "#"))))
+       (mapv (fn [line] (/ (Double/parseDouble line) 2)))))
+
+(defn parse-bin
+  "Parses a --bin spec. A single path is returned as is. A comma-separated list
+  of node=path pairs becomes a map of nodes to paths; a path without a node
+  goes under :default."
+  [spec]
+  (if-not (re-find #"[,=]" spec)
+    spec
+    (->> (str/split spec #"\s*,\s*")
+         (map (fn [entry]
+                (let [[node bin] (str/split entry #"\s*=\s*" 2)]
+                  (if bin
+                    [node bin]
+                    [:default node]))))
+         (into {}))))
+
 (def test-opt-spec
   "Options for single tests."
-  [[nil "--bin FILE"        "Path to binary which runs a node"
-    :missing "Expected a --bin PATH_TO_BINARY to test"]
+  [[nil "--bin FILE"        "Path to binary which runs a node. To run different binaries on different nodes, give a comma-separated list like `n1=v1.rb,n2=v2.rb`; a path without a node applies to every node not otherwise listed."
+    :missing "Expected a --bin PATH_TO_BINARY to test"
+    :parse-fn parse-bin]
 
    ["-w" "--workload NAME" "What workload to run."
     :default "lin-kv"
@@ -123,13 +214,55 @@
 
 (def opt-spec
   "Extra options for the CLI"
-  [[nil "--consistency-models MODELS" "A comma-separated list of consistency models to check."
+  [[nil "--bandwidth RATE" "Limits how fast each node can send messages to other nodes, in --bandwidth-units per second. Messages queue up behind one another while the sender is busy."
+    :parse-fn #(Double/parseDouble %)
+    :validate [pos? "Must be positive"]]
+
+   [nil "--bandwidth-scope SCOPE" "Either `node`, where the --bandwidth limit applies to all of a node's outbound traffic together, or `link`, where each destination gets its own limit."
+    :default :node
+    :parse-fn keyword
+    :validate [#{:node :link} "Must be node or link"]]
+
+   [nil "--bandwidth-unit UNIT" "Either `msgs`, for messages per second, or `bytes`, for bytes of JSON message bodies per second."
+    :default :msgs
+    :parse-fn keyword
+    :validate [#{:msgs :bytes} "Must be msgs or bytes"]]
+
+   [nil "--client-latency MILLIS" "Mean network latency for messages to and from clients, in ms. Clients have no latency unless this is given; adding latency to clients tends to hide anomalies."
+    :parse-fn parse-long
+    :validate [(complement neg?) "Must be non-negative"]]
+
+   [nil "--client-latency-dist TYPE" "Kind of latency distribution to use for clients. Defaults to --latency-dist."
+    :parse-fn keyword
+    :validate [net/latency-dists (cli/one-of net/latency-dists)]]
+
+   [nil "--codec CODEC" "How messages to and from nodes are encoded: `json`, as newline-delimited JSON, or `cbor` or `msgpack`, as length-prefixed binary frames. Nodes find the codec in the MAELSTROM_CODEC environment variable."
+    :default :json
+    :parse-fn keyword
+    :validate [codec/codecs (cli/one-of codec/codecs)]]
+
+   [nil "--consistency-models MODELS" "A comma-separated list of consistency models to check."
     :default [:strict-serializable]
     :parse-fn (fn [s]
                 (map keyword (str/split s #"\s+,\s+")))
     :validate [(partial every? cm/friendly-model-name)
                (cli/one-of (sort (map cm/friendly-model-name cm/all-models)))]]
 
+   [nil "--crash-points FILE" "An EDN file of crash points, which kill (and optionally restart) a node right after it receives, or right before it sends, specific messages. See maelstrom.net.crash."
+    :parse-fn crash/load-crash-points]
+
+   [nil "--inbox-capacity INT" "Roughly how many unread messages each node's inbox can hold. When a node reads from its inbox, messages which arrived while it was already full are dropped. Can't be used with --virtual-time."
+    :parse-fn parse-long
+    :validate [pos? "Must be positive"]]
+
+   [nil "--inter-region-latency MILLIS" "Mean network latency between nodes in different regions, in ms. Defaults to --latency."
+    :parse-fn parse-long
+    :validate [(complement neg?) "Must be non-negative"]]
+
+   [nil "--inter-region-latency-dist TYPE" "Kind of latency distribution to use between regions. Defaults to --latency-dist."
+    :parse-fn keyword
+    :validate [net/latency-dists (cli/one-of net/latency-dists)]]
+
    [nil "--key-count INT" "For the append test, how many keys should we test at once?"
     :parse-fn parse-long
     :validate [pos? "must be positive"]]
@@ -139,11 +272,18 @@
     :parse-fn parse-long
     :validate [(complement neg?) "Must be non-negative"]]
 
-   [nil "--latency-dist TYPE" "Kind of latency distribution to use."
+   [nil "--latency-dist TYPE" "Kind of latency distribution to use. The empirical distribution samples from --latency-trace."
     :default :constant
     :parse-fn keyword
-    :validate [#{:constant :uniform :exponential}
-               "Must be constant, uniform, or exponential"]]
+    :validate [net/latency-dists (cli/one-of net/latency-dists)]]
+
+   [nil "--latency-matrix FILE" "An EDN file mapping [src-region dest-region] pairs to latency maps like {:mean 100, :dist :exponential}. Overrides --inter-region-latency for those pairs. A pair also applies in reverse, unless the reverse is given explicitly."
+    :parse-fn (comp edn/read-string slurp)
+    :validate [map? "Must be an EDN map"]]
+
+   [nil "--latency-trace FILE" "A file of recorded round-trip times, in milliseconds, one per line. The empirical latency distribution samples half of each, as one-way latencies."
+    :parse-fn load-latency-trace
+    :validate [seq "Must contain at least one RTT"]]
 
    [nil "--log-net-send"    "Log packets as they're sent"
     :default false]
@@ -164,6 +304,14 @@
     :parse-fn parse-long
     :validate [pos? "must be positive"]]
 
+   [nil "--net-ordering MODE" "Either `unordered`, in which messages may be reordered whenever latency varies, or `fifo`, in which messages between any pair of nodes are delivered in the order they were sent, like a TCP connection."
+    :default :unordered
+    :parse-fn keyword
+    :validate [#{:unordered :fifo} "Must be unordered or fifo"]]
+
+   [nil "--net-rules FILE" "An EDN file of rules which drop, delay, duplicate, or hold specific messages. See maelstrom.net.rules."
+    :parse-fn rules/load-rules]
+
    [nil "--node-count NUM" "How many nodes to run. Overrides --nodes, if given."
     :default nil
     :parse-fn parse-long
@@ -177,21 +325,96 @@
                      set))
     :validate [(partial every? nemeses) (cli/one-of nemeses)]]
 
+   [nil "--nemesis-clock-skew MILLIS" "The largest amount, in ms, that the clock nemesis skews a node's clock by."
+    :default  10000
+    :parse-fn parse-long
+    :validate [pos? "Must be positive."]]
+
+   [nil "--nemesis-corrupt-p FLOAT" "While the corrupt nemesis is active, the probability that each message between servers has its body corrupted."
+    :default  0.1
+    :parse-fn #(Double/parseDouble %)
+    :validate [#(<= 0 % 1) "Must be between 0 and 1"]]
+
+   [nil "--nemesis-duplicate-p FLOAT" "While the duplicate nemesis is active, the probability that each message between servers is delivered twice."
+    :default  0.5
+    :parse-fn #(Double/parseDouble %)
+    :validate [#(<= 0 % 1) "Must be between 0 and 1"]]
+
+   [nil "--nemesis-equivocate-count NUM" "How many Byzantine nodes the equivocate nemesis picks each time it starts."
+    :default  1
+    :parse-fn parse-long
+    :validate [pos? "Must be positive."]]
+
+   [nil "--nemesis-flaky-p FLOAT" "While the flaky nemesis is active, the probability that each message is lost."
+    :default  0.5
+    :parse-fn #(Double/parseDouble %)
+    :validate [#(<= 0 % 1) "Must be between 0 and 1"]]
+
    [nil "--nemesis-interval SECONDS" "How many seconds between nemesis operations, on average?"
     :default  10
     :parse-fn read-string
     :validate [pos? "Must be positive"]]
 
+   [nil "--nemesis-partitions SHAPES" "A comma-separated list of partition shapes for the partition nemesis to choose from."
+    :default nemesis/default-partition-shapes
+    :parse-fn (fn [string]
+                (->> (str/split string #"\s*,\s*")
+                     (mapv keyword)))
+    :validate [(partial every? nemesis/partition-shapes)
+               (cli/one-of nemesis/partition-shapes)]]
+
+   [nil "--nemesis-slow-factor FLOAT" "While the slow nemesis is active, how many times slower is the network?"
+    :default  10
+    :parse-fn #(Double/parseDouble %)
+    :validate [pos? "Must be positive"]]
+
+   [nil "--nemesis-slow-node-duty FLOAT" "While the slow-node nemesis is active, the fraction of the time the slow node is allowed to run; it's paused the rest of the time."
+    :default  0.1
+    :parse-fn #(Double/parseDouble %)
+    :validate [#(< 0 % 1) "Must be between 0 and 1"]]
+
+   [nil "--p-duplicate FLOAT" "The probability that any given message between servers is delivered twice, throughout the test."
+    :default  0
+    :parse-fn #(Double/parseDouble %)
+    :validate [#(<= 0 % 1) "Must be between 0 and 1"]]
+
    [nil "--rate RATE" "Approximate number of request/sec"
     :default  5
     :parse-fn #(Double/parseDouble %)
     :validate [(complement neg?) "Can't be negative"]]
 
+   [nil "--regions SPEC" "Assigns nodes to regions, for latency purposes. Either a number of regions to split nodes evenly into (named r0, r1, ...), or an explicit spec like \"east=n1,n2;west=n3,n4\"."]
+
+   [nil "--seed INT" "A seed for Maelstrom's random choices: latencies, message loss, services, generated operations, nemesis timing and targets, and the like. Nodes and clients still run in real time, so two runs with the same seed may differ in timing. If omitted, Maelstrom picks and logs one."
+    :parse-fn parse-long]
+
+   [nil "--spare-nodes NUM" "How many extra nodes the membership nemesis can add to the cluster, beyond --node-count."
+    :default  2
+    :parse-fn parse-long
+    :validate [(complement neg?) "Can't be negative"]]
+
    [nil "--topology SPEC" "What kind of network topology to offer to nodes, for those workloads (e.g. broadcast) which use one."
     :parse-fn keyword
     :default :grid
     :validate [broadcast/topologies (cli/one-of broadcast/topologies)]]
 
+   [nil "--topology-file FILE" "For broadcast tests, a JSON file with an adjacency map of node IDs to arrays of neighbor IDs, used instead of --topology. Links must go both ways, and connect every node."
+    :parse-fn broadcast/load-topology-file]
+
+   [nil "--topology-change-interval SECONDS" "For broadcast tests, change the topology, and send nodes fresh topology messages, about this often. If omitted, the topology never changes."
+    :parse-fn read-string
+    :validate [pos? "Must be positive"]]
+
+   [nil "--transport TRANSPORT" "How nodes exchange messages with Maelstrom: `stdio`, for JSON lines on stdin and stdout, or `tcp` or `unix`, for JSON lines on a local socket. Nodes find the socket's address in the MAELSTROM_ADDRESS environment variable."
+    :default :stdio
+    :parse-fn keyword
+    :validate [process/transports (cli/one-of process/transports)]]
+
+   [nil "--upgrade-bin FILE" "For the upgrade nemesis, the binary to restart nodes with."]
+
+   [nil "--virtual-time" "Deliver network messages in virtual time, one at a time, in deadline order. Removes timing jitter from the network's own choices, at the cost of throughput: each delivery waits a few ms for the recipient to reply."
+    :default false]
+
    ])
 
 (defn parse-node-count
@@ -204,14 +427,190 @@
     parsed))
 
 (defn parse-latency
-  "Moves latency and latency-dist into their own :latency map."
+  "Moves latency, latency-dist, and latency-trace into their own :latency map,
+  and likewise for client-latency."
+  [parsed]
+  (let [o      (:options parsed)
+        trace  (:latency-trace o)
+        parsed (assoc parsed :options
+                      (-> o
+                          (assoc :latency {:mean  (:latency o)
+                                           :dist  (:latency-dist o)
+                                           :trace trace})
+                          (assoc :client-latency
+                                 (when-let [mean (:client-latency o)]
+                                   {:mean  mean
+                                    :dist  (:client-latency-dist
+                                             o (:latency-dist o))
+                                    :trace trace}))
+                          (dissoc :latency-dist :client-latency-dist
+                                  :latency-trace)))]
+    (if (and (nil? trace)
+             (some #{:empirical} [(:latency-dist o)
+                                  (:client-latency-dist o)
+                                  (:inter-region-latency-dist o)]))
+      (update parsed :errors conj
+              "The empirical latency distribution requires --latency-trace")
+      parsed)))
+
+(defn parse-regions-spec
+  "Parses a --regions spec, given a collection of nodes, into a map of node IDs
+  to region names. See the --regions option."
+  [nodes spec]
+  (if (re-matches #"\d+" spec)
+    (let [n (parse-long spec)
+          c (count nodes)]
+      (->> nodes
+           (map-indexed (fn [i node]
+                          [node (str "r" (quot (* i n) c))]))
+           (into {})))
+    (->> (str/split spec #"\s*;\s*")
+         (mapcat (fn [region]
+                   (let [[region nodes] (str/split region #"\s*=\s*" 2)]
+                     (for [node (str/split (or nodes "") #"\s*,\s*")
+                           :when (not= "" node)]
+                       [node region]))))
+         (into {}))))
+
+(defn region-latencies
+  "Takes a map of nodes to regions, a latency specification for links between
+  regions, and a latency matrix (see --latency-matrix), and builds a map of
+  [src-region dest-region] pairs to latency specifications. Matrix entries
+  share the inter-region spec's :trace."
+  [regions inter-latency matrix]
+  (let [names (distinct (vals regions))
+        matrix (->> matrix
+                    (map (fn [[link spec]]
+                           [link (merge (select-keys inter-latency [:trace])
+                                        spec)]))
+                    (into {}))
+        ; Matrix entries apply in both directions, unless given explicitly.
+        matrix (reduce (fn [m [[a b] spec]]
+                         (if (contains? m [b a])
+                           m
+                           (assoc m [b a] spec)))
+                       matrix
+                       matrix)]
+    (merge (into {} (for [a names, b names :when (not= a b)]
+                      [[a b] inter-latency]))
+           matrix)))
+
+(def LatencyMatrix
+  "The schema for a --latency-matrix file."
+  {[(s/one s/Str "src-region") (s/one s/Str "dest-region")]
+   {:mean s/Num
+    :dist (apply s/enum net/latency-dists)}})
+
+(defn parse-regions
+  "Takes parsed options and, if --regions is given, replaces it with a map of
+  node IDs to region names, and computes :region-latencies."
+  [parsed]
+  (let [o      (:options parsed)
+        matrix (:latency-matrix o)]
+    (if-let [spec (:regions o)]
+      (let [nodes   (:nodes o)
+            regions (parse-regions-spec nodes spec)
+            unknown (remove (set nodes) (keys regions))]
+        (cond
+          (seq unknown)
+          (update parsed :errors conj
+                  (str "--regions mentions nodes which aren't in this test: "
+                       (str/join ", " unknown)))
+
+          (and matrix (s/check LatencyMatrix matrix))
+          (update parsed :errors conj
+                  (str "Malformed --latency-matrix: "
+                       (pr-str (s/check LatencyMatrix matrix))))
+
+          (and (nil? (:trace (:latency o)))
+               (some (comp #{:empirical} :dist) (vals matrix)))
+          (update parsed :errors conj
+                  "The empirical latency distribution requires --latency-trace")
+
+          true
+          (let [latency (:latency o)
+                inter   {:mean  (:inter-region-latency o (:mean latency))
+                         :dist  (:inter-region-latency-dist o (:dist latency))
+                         :trace (:trace latency)}]
+            (assoc parsed :options
+                   (assoc o
+                          :regions regions
+                          :region-latencies (region-latencies
+                                              regions
+                                              inter
+                                              matrix))))))
+      (if matrix
+        (update parsed :errors conj "--latency-matrix requires --regions")
+        parsed))))
+
+(defn check-bins
+  "Takes parsed options, and ensures that every node has a binary to run,
+  including the spare nodes the membership nemesis may add, and that the
+  upgrade nemesis has one to upgrade to."
+  [parsed]
+  (let [o       (:options parsed)
+        bin     (:bin o)
+        nodes   (concat (:nodes o)
+                        (when (contains? (set (:nemesis o)) :membership)
+                          (membership/spare-nodes (:nodes o)
+                                                  (:spare-nodes o))))
+        missing (when (and (map? bin) (not (:default bin)))
+                  (remove (set (keys bin)) nodes))]
+    (cond-> parsed
+      (seq missing)
+      (update :errors conj (str "--bin doesn't give a binary for nodes: "
+                                (str/join ", " missing)))
+
+      (and (contains? (set (:nemesis o)) :upgrade)
+           (not (:upgrade-bin o)))
+      (update :errors conj "The upgrade nemesis requires --upgrade-bin"))))
+
+(defn check-topology-file
+  "Takes parsed options, and ensures that a --topology-file only mentions
+  nodes in this test, that its links go both ways, and that every node can
+  reach every other."
+  [parsed]
+  (let [o          (:options parsed)
+        topo       (:topology-file o)
+        nodes      (:nodes o)
+        unknown    (->> (concat (keys topo) (apply concat (vals topo)))
+                        (remove (set nodes))
+                        distinct)
+        asymmetric (for [[node neighbors] topo
+                         neighbor         neighbors
+                         :when (not (some #{node} (get topo neighbor)))]
+                     (str node " -> " neighbor))]
+    (cond
+      (nil? topo)
+      parsed
+
+      (seq unknown)
+      (update parsed :errors conj
+              (str "--topology-file mentions nodes which aren't in this test: "
+                   (str/join ", " unknown)))
+
+      (seq asymmetric)
+      (update parsed :errors conj
+              (str "--topology-file has links with no link back: "
+                   (str/join ", " asymmetric)))
+
+      (not (broadcast/connected? (merge (zipmap nodes (repeat [])) topo)))
+      (update parsed :errors conj
+              "--topology-file doesn't connect every node in this test")
+
+      true
+      parsed)))
+
+(defn check-inbox-capacity
+  "Takes parsed options, and ensures we don't try to bound inboxes in virtual
+  time. The virtual-time scheduler hands each node one message at a time, so
+  its inbox never fills."
   [parsed]
   (let [o (:options parsed)]
-    (assoc parsed :options
-           (-> o
-               (assoc :latency {:mean (:latency o)
-                                :dist (:latency-dist o)})
-               (dissoc :latency-dist)))))
+    (if (and (:inbox-capacity o) (:virtual-time o))
+      (update parsed :errors conj
+              "--inbox-capacity can't be used with --virtual-time")
+      parsed)))
 
 (defn add-args
   "Adds non-option arguments as :args into parsed options map. :args value is
@@ -227,8 +626,13 @@
   (-> parsed
       parse-latency
       parse-node-count
+      check-inbox-capacity
       add-args
-      cli/test-opt-fn))
+      cli/test-opt-fn
+      ; These need the final list of nodes, so they come last.
+      parse-regions
+      check-bins
+      check-topology-file))
 
 (defn -main
   [& args]
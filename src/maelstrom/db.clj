@@ -7,76 +7,348 @@
             [maelstrom [client :as client]
                        [net :as net]
                        [process :as process]
-                       [service :as service]]
+                       [service :as service]
+                       [util :as u]]
+            [maelstrom.net [journal :as j]]
             [slingshot.slingshot :refer [try+ throw+]]))
 
+(defn data-dir
+  "The directory where a node keeps its durable state. This lives in the
+  test's store directory, so it survives the node being killed and restarted,
+  and is archived along with the rest of the test's results."
+  [test node-id]
+  (doto ^java.io.File (store/path test "node-data" node-id)
+    (.mkdirs)))
+
+(defn bin-for
+  "Which binary should a node run, given db options? :bin is either a single
+  path for every node, or a map of node IDs to paths, where :default, if
+  present, covers any node not otherwise listed."
+  [opts node-id]
+  (let [bin (:bin opts)]
+    (if (map? bin)
+      (get bin node-id (:default bin))
+      bin)))
+
+(defn start-node!
+  "Starts a node's process, given db options, a test, and a node ID. Returns
+  the process map from process/start-node!."
+  [opts test node-id]
+  (process/start-node!
+    {:node-id  node-id
+     :bin      (bin-for opts node-id)
+     :args     (:args opts)
+     :net      (:net opts)
+     :transport (:transport opts)
+     :codec    (:codec opts)
+     :dir      (System/getProperty "java.io.tmpdir")
+     :data-dir (.getCanonicalPath (data-dir test node-id))
+     :log-stderr? (:log-stderr test)
+     :log-file (->> (str node-id ".log")
+                    (store/path test "node-logs")
+                    .getCanonicalPath)}))
+
+(defn init-node!
+  "Sends an init message to a freshly started node, telling it about the given
+  cluster members, and waits for it to respond. Throws if it doesn't."
+  [net node-id node-ids]
+  (let [client (client/open! net)]
+    (try+
+      (let [res (client/rpc!
+                  client
+                  node-id
+                  {:type "init"
+                   :node_id node-id
+                   :node_ids (vec node-ids)}
+                  10000)]
+        (when (not= "init_ok" (:type res))
+          (throw+ {:type      :init-failed
+                   :node      node-id
+                   :response  res}
+                  nil
+                  (str "Expected an init_ok message, but node responded with "
+                       (pr-str res)))))
+      (catch [:type :maelstrom.client/timeout] e
+        (throw+ {:type :init-failed
+                 :node node-id}
+                (:throwable &throw-context)
+                (str "Expected node " node-id
+                     " to respond to an init message, but node did not respond.")))
+      (finally
+        (client/close! client)))))
+
+(defprotocol Amnesia
+  (wipe! [db test node]
+         "Erases a node's durable state: its data directory, and its disk
+         service storage. The node should be down."))
+
+(defprotocol Upgrade
+  (upgrade! [db test node bin]
+            "Stops a node, and restarts it running a different binary. Returns
+            a map of {:node node, :from old-bin, :to new-bin}."))
+
+(defprotocol Throttle
+  (throttle! [db test node duty period]
+             "Duty-cycles a node's process with SIGSTOP and SIGCONT, so that
+             it runs for only a fraction (duty, between 0 and 1) of every
+             period ms: alive, but sluggish. A node paused by the pause
+             nemesis stays paused until it's resumed.")
+  (unthrottle! [db test node]
+               "Stops throttling a node, and lets it run freely."))
+
+(defprotocol Membership
+  (members [db test]
+           "The set of nodes currently in the cluster. Initially, the test's
+           nodes.")
+  (join! [db test node]
+         "Starts a node which isn't in the cluster, initializes it with the
+         new membership, and adds it to the cluster.")
+  (leave! [db test node]
+          "Decommissions a node: stops it, removes it from the network, and
+          erases its durable state. Messages to it are lost, and client
+          requests to it fail with node-not-found."))
+
+(defn duty-cycle!
+  "Spawns a future which repeatedly resumes and pauses whatever process
+  (get-process) returns, running it for duty * period ms out of every period
+  ms, until running? is false. Then resumes it one last time. Whenever
+  (get-process) returns nil, we leave the node alone. We hold lock while
+  calling get-process and signalling the process, so that callers can keep
+  the process from changing under us."
+  [running? lock get-process duty period]
+  (let [run-ms   (long (* duty period))
+        pause-ms (- (long period) run-ms)
+        signal!  (fn [f]
+                   ; The node may have been killed, or paused by the pause
+                   ; nemesis, out from under us
+                   (locking lock
+                     (when-let [p (get-process)]
+                       (try (f p)
+                            (catch Exception e
+                              (warn e "Couldn't signal throttled node"))))))]
+    (future
+      (while @running?
+        (signal! process/resume-node!)
+        (Thread/sleep run-ms)
+        (when (pos? pause-ms)
+          (signal! process/pause-node!)
+          (Thread/sleep pause-ms)))
+      (signal! process/resume-node!))))
+
+(defn crash!
+  "Kills a node which hit a crash point (see maelstrom.net.crash), and, if the
+  crash point has a :restart delay, restarts it after that many ms. Epoch is
+  an atom which the db increments when it tears down: if it's changed by the
+  time we'd restart, the node stays down. We restart while holding epoch's
+  lock, so teardown can't slip in halfway through."
+  [db test node {:keys [name restart]} epoch]
+  (let [e @epoch]
+    (try
+      (info "Crashing" node "at crash point" name)
+      (db/kill! db test node)
+      (when restart
+        (Thread/sleep (long restart))
+        (locking epoch
+          (if (= e @epoch)
+            (db/start! db test node)
+            (info "Not restarting" node "after crash point" name
+                  "since the test is tearing down"))))
+      (catch Throwable t
+        (warn t "Error crashing" node "at crash point" name)))))
+
+(defn wipe-data-dir!
+  "Deletes everything in a node's data directory."
+  [test node-id]
+  (let [dir ^java.io.File (data-dir test node-id)]
+    (doseq [^java.io.File f (reverse (file-seq dir))
+            :when (not= f dir)]
+      (.delete f))))
+
 (defn db
   "Options:
 
-      :bin - a binary to run
+      :bin - a binary to run, or a map of node IDs to binaries (see bin-for)
       :args - args to that binary
-      :net - a network"
+      :net - a network
+      :transport - how nodes exchange messages; see maelstrom.process
+      :codec - how messages are encoded; see maelstrom.codec
+      :clock - the Clock service behind `time` and `lin-lock`; by default,
+               one which follows real time
+      :services - a map of node IDs to extra services to run, beyond the
+                  defaults"
   [opts]
   (let [net       (:net opts)
         services  (atom nil)
-        processes (atom {})]
+        processes (atom {})
+        ; Nodes which have been upgraded, mapped to their new binaries
+        upgraded  (atom {})
+        ; Nodes being throttled, mapped to {:running? atom, :worker future}
+        throttles (atom {})
+        ; Nodes paused by the pause nemesis, which throttling mustn't resume
+        paused    (atom #{})
+        ; Incremented on every teardown, so crash points know not to
+        ; restart nodes; see crash!
+        epoch     (atom 0)
+        ; The current cluster membership, once it's changed. Nil means the
+        ; test's nodes.
+        members   (atom nil)
+        members-of (fn [test] (or @members (set (:nodes test))))
+        node-opts (fn [node]
+                    (if-let [bin (get @upgraded node)]
+                      (assoc opts :bin bin)
+                      opts))]
     (reify db/DB
-      (setup! [_ test node-id]
-        ; Spawn built-in Maelstrom services
+      (setup! [this test node-id]
         (when (= (jepsen/primary test) node-id)
+          ; Spawn built-in Maelstrom services
           (reset! services (service/start-services!
                              net
-                             (service/default-services test))))
+                             (merge (service/default-services
+                                      test
+                                      (or (:clock opts) (service/clock)))
+                                    (:services opts))))
+          ; Kill nodes which hit crash points
+          (net/on-crash! net (fn [node point]
+                               (future (crash! this test node point epoch)))))
 
         ; Start this node
         (info "Setting up" node-id)
         (swap! processes assoc node-id
-               (process/start-node!
-                 {:node-id  node-id
-                  :bin      (:bin opts)
-                  :args     (:args opts)
-                  :net      net
-                  :dir      (System/getProperty "java.io.tmpdir")
-                  :log-stderr? (:log-stderr test)
-                  :log-file (->> (str node-id ".log")
-                                 (store/path test "node-logs")
-                                 .getCanonicalPath)}))
+               (start-node! (node-opts node-id) test node-id))
 
         ; Initialize this node
-        (let [client (client/open! net)]
-          (try+
-            (let [res (client/rpc!
-                        client
-                        node-id
-                        {:type "init"
-                         :node_id node-id
-                         :node_ids (:nodes test)}
-                        10000)]
-              (when (not= "init_ok" (:type res))
-                (throw+ {:type      :init-failed
-                         :node      node-id
-                         :response  res}
-                        nil
-                        (str "Expected an init_ok message, but node responded with "
-                             (pr-str res)))))
-            (catch [:type :maelstrom.client/timeout] e
-              (throw+ {:type :init-failed
-                       :node node-id}
-                      (:throwable &throw-context)
-                      (str "Expected node " node-id
-                           " to respond to an init message, but node did not respond.")))
-            (finally
-              (client/close! client)))))
+        (init-node! net node-id (:nodes test)))
 
       (teardown! [_ test node]
+        ; Crash points mustn't restart nodes once we've torn them down
+        (locking epoch
+          (swap! epoch inc))
+
         ; Tear down node
         (when-let [p (get @processes node)]
           (info "Tearing down" node)
           (process/stop-node! p)
           (swap! processes dissoc node))
 
-        ; Tear down services
         (when (= node (jepsen/primary test))
+          ; Tear down nodes which joined mid-test
+          (doseq [[node p] @processes
+                  :when (not (some #{node} (:nodes test)))]
+            (info "Tearing down" node)
+            (process/stop-node! p)
+            (swap! processes dissoc node))
+
+          ; Tear down services
           (when-let [s @services]
             (service/stop-services! s)
-            (reset! services nil)))))))
+            (reset! services nil))))
+
+      db/Process
+      (start! [_ test node]
+        ; We only start members which have been killed.
+        (when (and (not (get @processes node))
+                   (contains? (members-of test) node))
+          (info "Restarting" node)
+          (swap! processes assoc node (start-node! (node-opts node) test node))
+          (init-node! net node (u/sort-clients (members-of test)))
+          :restarted))
+
+      (kill! [_ test node]
+        (when-let [p (get @processes node)]
+          (info "Killing" node)
+          (swap! processes dissoc node)
+          (swap! paused disj node)
+          ; Leave the node in the network while it's down, so its peers can
+          ; keep sending to it. Those messages are lost.
+          (try+ (process/stop-node! p {:leave-net? true})
+                (catch [:type :node-crashed] e
+                  (warn "Node" node "had already crashed before we killed it:"
+                        (:exit e))))
+          ; And so are its unsynced writes.
+          (when-let [disk (get-in @services [:services "disk"])]
+            (service/crash! disk node))
+          :killed))
+
+      ; Pauses are journaled, so they show up in the Lamport diagram.
+      db/Pause
+      (pause! [_ test node]
+        (when-let [p (get @processes node)]
+          (locking paused
+            (swap! paused conj node)
+            (process/pause-node! p))
+          (net/down! net node)
+          (j/log-node-event! (:journal @net) :pause node)
+          :paused))
+
+      (resume! [_ test node]
+        (when-let [p (get @processes node)]
+          (net/up! net node)
+          (locking paused
+            (swap! paused disj node)
+            (process/resume-node! p))
+          (j/log-node-event! (:journal @net) :resume node)
+          :resumed))
+
+      Upgrade
+      (upgrade! [this test node bin]
+        (let [old (bin-for (node-opts node) node)]
+          (info "Upgrading" node "from" old "to" bin)
+          (db/kill! this test node)
+          (swap! upgraded assoc node bin)
+          (db/start! this test node)
+          {:node node, :from old, :to bin}))
+
+      Throttle
+      (throttle! [this test node duty period]
+        (unthrottle! this test node)
+        (info "Throttling" node "to" duty "of every" period "ms")
+        (let [running? (atom true)]
+          (swap! throttles assoc node
+                 {:running? running?
+                  :worker   (duty-cycle! running? paused
+                                         #(when-not (contains? @paused node)
+                                            (get @processes node))
+                                         duty period)})
+          :throttled))
+
+      (unthrottle! [_ test node]
+        (when-let [{:keys [running? worker]} (get @throttles node)]
+          (reset! running? false)
+          @worker
+          (swap! throttles dissoc node)
+          :unthrottled))
+
+      Membership
+      (members [_ test]
+        (members-of test))
+
+      (join! [_ test node]
+        (info "Joining" node)
+        (let [members' (conj (members-of test) node)]
+          (swap! processes assoc node (start-node! (node-opts node) test node))
+          (init-node! net node (u/sort-clients members'))
+          (reset! members members')
+          :joined))
+
+      (leave! [this test node]
+        (info "Decommissioning" node)
+        (reset! members (disj (members-of test) node))
+        (net/depart! net node)
+        (if-let [p (get @processes node)]
+          (do (swap! processes dissoc node)
+              (try+ (process/stop-node! p)
+                    (catch [:type :node-crashed] e
+                      (warn "Node" node "had already crashed before it left:"
+                            (:exit e)))))
+          ; Killed nodes stay in the network; take them out too.
+          (net/remove-node! net node))
+        (wipe! this test node)
+        :left)
+
+      Amnesia
+      (wipe! [_ test node]
+        (info "Wiping" node)
+        (wipe-data-dir! test node)
+        (when-let [disk (get-in @services [:services "disk"])]
+          (service/wipe! disk node))
+        :wiped))))
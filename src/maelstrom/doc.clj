@@ -20,31 +20,50 @@
   [s]
   (str/replace s #"(^|\n)[ \t]+" "$1"))
 
+(defn section-title
+  "RPCs are documented in sections, one per namespace. Takes a namespace name
+  and returns the title of its section: \"Workload: Echo\" for
+  maelstrom.workload.echo, or just \"Membership\" for maelstrom.membership,
+  which isn't a workload."
+  [ns-name]
+  (let [short (str/capitalize (last (str/split ns-name #"\.")))]
+    (if (str/starts-with? ns-name "maelstrom.workload.")
+      (str "Workload: " short)
+      short)))
+
+(defn anchor
+  "The Markdown anchor for a section title."
+  [title]
+  (-> title
+      str/lower-case
+      (str/replace #"[^a-z0-9 -]" "")
+      (str/replace " " "-")))
+
 (defn print-workloads
-  "Prints out all workloads to stdout, based on the client RPC registry."
+  "Prints out all workloads to stdout, based on the client RPC registry.
+  RPCs from other namespaces, like membership, follow the workloads."
   ([]
    (print-workloads @c/rpc-registry))
   ([rpcs]
    ; Group RPCs by namespace
    (let [ns->rpcs (->> rpcs
-                       (group-by (fn [rpc]
-                                   (-> rpc
-                                       :ns
-                                       ns-name
-                                       name
-                                       (str/split #"\.")
-                                       last)))
-                       (sort-by key))]
+                       (group-by (comp name ns-name :ns))
+                       (sort-by (fn [[ns _]]
+                                  [(not (str/starts-with?
+                                          ns "maelstrom.workload."))
+                                   (last (str/split ns #"\."))])))]
 
      (println (slurp (io/resource "workloads-intro.md")) "\n")
 
      (println "## Table of Contents\n")
      (doseq [[ns rpcs] ns->rpcs]
-       (println (str "- [" (str/capitalize ns) "](#workload-" ns ")")))
+       (let [title (section-title ns)]
+         (println (str "- [" (str/replace title #"^Workload: " "")
+                       "](#" (anchor title) ")"))))
      (println)
 
      (doseq [[ns rpcs] ns->rpcs]
-       (println "## Workload:" (str/capitalize ns) "\n")
+       (println (str "## " (section-title ns)) "\n")
 
        (println (unindent (:doc (meta (:ns (first rpcs))))) "\n")
 
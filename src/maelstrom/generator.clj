@@ -0,0 +1,75 @@
+(ns maelstrom.generator
+  "Generators whose random choices come from maelstrom.random, so that a
+  --seed fixes them.
+
+  Jepsen may ask a generator for an operation several times before it uses
+  one, so a generator can't simply draw from a shared stream: how many draws
+  it made would depend on timing. Instead, `seeded` carries a seed as part of
+  the generator's state, and binds a fresh RNG from that seed whenever it's
+  asked for an operation. Asking twice gives the same answer; only emitting an
+  operation moves on to the next seed. `stagger` and `mix` are like Jepsen's,
+  but draw from maelstrom.random, and so are fixed by an enclosing `seeded`."
+  (:require [jepsen [generator :as gen]
+                    [util :as util]]
+            [maelstrom.random :as r])
+  (:import (java.util Random)))
+
+(defrecord Seeded [^long seed gen]
+  gen/Generator
+  (op [this test ctx]
+    (let [rng (Random. seed)]
+      (when-let [[op gen'] (r/with-rng rng (gen/op gen test ctx))]
+        [op (Seeded. (if (= :pending op) seed (.nextLong rng)) gen')])))
+
+  (update [this test ctx event]
+    (Seeded. seed (r/with-rng (Random. seed)
+                    (gen/update gen test ctx event)))))
+
+(defn seeded
+  "Wraps a generator so that whatever it draws from maelstrom.random depends
+  only on the root seed, the given key (e.g. :generator), and the operations
+  it has emitted so far."
+  [k gen]
+  (Seeded. (.nextLong (r/rng k)) gen))
+
+(defrecord Stagger [dt next-time gen]
+  gen/Generator
+  (op [this test ctx]
+    (when-let [[op gen'] (gen/op gen test ctx)]
+      (if (= :pending op)
+        [op (Stagger. dt next-time gen')]
+        (let [t (max (:time op) (or next-time (:time ctx)))]
+          [(assoc op :time t)
+           (Stagger. dt (+ t (long (r/rand dt))) gen')]))))
+
+  (update [this test ctx event]
+    (Stagger. dt next-time (gen/update gen test ctx event))))
+
+(defn stagger
+  "Like jepsen.generator/stagger: introduces uniformly random delays between
+  operations, dt seconds apart on average."
+  [dt gen]
+  (Stagger. (long (util/secs->nanos (* 2 dt))) nil gen))
+
+(defrecord Mix [i gens]
+  gen/Generator
+  (op [this test ctx]
+    (when (seq gens)
+      (if-let [[op gen'] (gen/op (nth gens i) test ctx)]
+        [op (Mix. (if (= :pending op) i (r/rand-int (count gens)))
+                  (assoc gens i gen'))]
+        ; This generator is exhausted; try the others.
+        (let [gens (into (subvec gens 0 i) (subvec gens (inc i)))]
+          (when (seq gens)
+            (gen/op (Mix. (r/rand-int (count gens)) gens) test ctx))))))
+
+  (update [this test ctx event]
+    this))
+
+(defn mix
+  "Like jepsen.generator/mix: a random mixture of several generators. Takes a
+  collection of generators, and chooses between them uniformly."
+  [gens]
+  (let [gens (vec gens)]
+    (when (seq gens)
+      (Mix. (r/rand-int (count gens)) gens))))
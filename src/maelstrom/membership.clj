@@ -0,0 +1,125 @@
+(ns maelstrom.membership
+  "With the membership nemesis, the cluster changes shape mid-test. Maelstrom
+  starts additional nodes partway through (spares, named after the test's
+  nodes: n5, n6, ...), and decommissions existing ones. A joining node's
+  `init` message lists the cluster as of its arrival, and every member is
+  then sent a `membership` message announcing the new configuration.
+
+  A node which leaves is stopped, and its durable state erased. Messages sent
+  to it are lost, and client requests to it fail with a definite
+  `node-not-found` error. At the end of the test, every one of the test's
+  original nodes rejoins the cluster, so final reads can reach them."
+  (:require [clojure.tools.logging :refer [info warn]]
+            [jepsen [generator :as gen]
+                    [nemesis :as n]]
+            [maelstrom [client :as c]
+                       [db :as db]
+                       [generator :as mgen]
+                       [random :as r]
+                       [util :as u]]
+            [schema.core :as s]
+            [slingshot.slingshot :refer [try+ throw+]]))
+
+(c/defrpc membership!
+  "Maelstrom sends every member of the cluster a `membership` message whenever
+  nodes join or leave. `node_ids` is the full, new set of members, including
+  the recipient. `joined` and `left` are the nodes which were added and
+  removed by this change. Servers should respond with a `membership_ok`
+  message.
+
+  Nodes which have left may still receive messages from other nodes for a
+  little while; nodes which have joined may receive messages from peers which
+  learned of them first."
+  {:type      (s/eq "membership")
+   :node_ids  [s/Str]
+   :joined    [s/Str]
+   :left      [s/Str]}
+  {:type      (s/eq "membership_ok")})
+
+(defn spare-nodes
+  "Given the test's nodes, constructs n additional node IDs which can join the
+  cluster later."
+  [nodes n]
+  (->> (range (count nodes) Long/MAX_VALUE)
+       (map (partial str "n"))
+       (remove (set nodes))
+       (take n)
+       vec))
+
+(defn announce!
+  "Tells every member of the cluster about a membership change. Members which
+  don't respond are logged, but otherwise ignored: they'll find out from their
+  peers, or from the next announcement."
+  [net members joined left]
+  (let [client   (c/open! net)
+        node-ids (vec (u/sort-clients members))]
+    (try
+      (doseq [node node-ids]
+        (try+ (membership! client node {:node_ids node-ids
+                                        :joined   (vec joined)
+                                        :left     (vec left)})
+              (catch [:type ::c/timeout] e
+                (warn "Node" node "didn't acknowledge membership change"))
+              (catch [:type :rpc-error] e
+                (warn "Node" node "rejected membership change:" (:body e)))))
+      (finally
+        (c/close! client)))))
+
+(defn membership-nemesis
+  "Changes the cluster's membership. Responds to:
+
+    {:f :join}      Starts a random non-member, drawn from the test's nodes
+                    and spares
+    {:f :leave}     Decommissions a random member, so long as at least one
+                    would remain
+    {:f :restore}   Rejoins every one of the test's nodes which has left
+
+  Completed ops have a value of {:joined [...], :left [...], :members [...]}."
+  [db net spares]
+  (reify n/Nemesis
+    (setup! [this test]
+      this)
+
+    (invoke! [this test op]
+      (let [members (db/members db test)
+            pool    (concat (:nodes test) spares)
+            [joined left]
+            (case (:f op)
+              :join    (if-let [node (first (r/shuffle (remove members pool)))]
+                         [[node] []]
+                         [[] []])
+              :leave   (if (< 1 (count members))
+                         [[] [(r/rand-nth (vec (u/sort-clients members)))]]
+                         [[] []])
+              :restore [(vec (remove members (:nodes test))) []])]
+        (doseq [node joined] (db/join! db test node))
+        (doseq [node left]   (db/leave! db test node))
+        (let [members' (db/members db test)]
+          (when (seq (concat joined left))
+            (announce! net members' joined left))
+          (assoc op :value {:joined  joined
+                            :left    left
+                            :members (vec (u/sort-clients members'))}))))
+
+    (teardown! [this test])
+
+    n/Reflection
+    (fs [this]
+      #{:join :leave :restore})))
+
+(defn package
+  "A nemesis package which alternates between decommissioning nodes and
+  adding new ones. (:membership opts) is a map of :net, the network, and
+  :spares, a collection of extra node IDs which can join."
+  [opts]
+  (let [{:keys [net spares]} (:membership opts)]
+    (when (contains? (:faults opts) :membership)
+      {:generator       (->> (gen/flip-flop
+                               (repeat {:type :info, :f :leave, :value nil})
+                               (repeat {:type :info, :f :join, :value nil}))
+                             (mgen/stagger (:interval opts)))
+       :final-generator {:type :info, :f :restore, :value nil}
+       :nemesis         (membership-nemesis (:db opts) net spares)
+       :perf            #{{:name  "membership"
+                           :fs    #{:join :leave :restore}
+                           :color "#E9A0B8"}}})))
@@ -1,16 +1,496 @@
 (ns maelstrom.nemesis
   "Fault injection"
   (:require [clojure.tools.logging :refer [info warn]]
-            [jepsen [generator :as gen]
+            [jepsen [db :as db]
+                    [generator :as gen]
                     [nemesis :as n]
                     [util :refer [pprint-str]]]
             [jepsen.nemesis.combined :as nc]
+            [jepsen.net :as jnet]
+            [maelstrom [db :as mdb]
+                       [generator :as mgen]
+                       [membership :as membership]
+                       [net :as net]
+                       [random :as r]
+                       [service :as service]]
             [slingshot.slingshot :refer [try+ throw+]]))
 
+(def partition-shapes
+  "A map of partition shapes to the colors we use for them in perf plots.
+
+    :one              Isolates a single node from all the others
+    :majority         Splits nodes into a majority and a minority
+    :majorities-ring  Every node can see a majority, but no two nodes see the
+                      same majority
+    :bridge           Two halves, connected only via a single bridge node
+    :random-halves    Two equal-as-possible halves
+    :one-way          One half stops receiving messages from the other half,
+                      but messages still flow in the other direction"
+  {:one             "#E9DCA0"
+   :majority        "#E9C3A0"
+   :majorities-ring "#E9A0A0"
+   :bridge          "#C7E9A0"
+   :random-halves   "#A0E9C8"
+   :one-way         "#D3A0E9"})
+
+(def default-partition-shapes
+  "Which partition shapes do we use, if not otherwise specified?"
+  [:one :majority :majorities-ring])
+
+(defn grudge
+  "Takes a collection of nodes and a partition shape, and computes a grudge: a
+  map of nodes to the collections of nodes they should drop messages from."
+  [nodes shape]
+  (let [nodes (r/shuffle nodes)]
+    (case shape
+      :one             (n/complete-grudge (n/split-one nodes))
+      :majority        (n/complete-grudge
+                         (split-at (quot (dec (count nodes)) 2) nodes))
+      :majorities-ring (n/majorities-ring nodes)
+      :bridge          (n/bridge nodes)
+      :random-halves   (n/complete-grudge (n/bisect nodes))
+      :one-way         (let [[deaf loud] (n/bisect nodes)]
+                         (zipmap deaf (repeat loud))))))
+
+(defn partition!
+  "Applies a grudge to the test's network, via jepsen.net/drop!."
+  [test grudge]
+  (doseq [[dest srcs] grudge
+          src         srcs
+          :when       (not= src dest)]
+    (jnet/drop! (:net test) test src dest)))
+
+(defn start-partition-f
+  "The :f we use to start a partition of the given shape, e.g.
+  :start-partition-bridge."
+  [shape]
+  (keyword (str "start-partition-" (name shape))))
+
+(def start-partition-fs
+  "A map of start-partition :fs to their shapes."
+  (->> (keys partition-shapes)
+       (map (juxt start-partition-f identity))
+       (into {})))
+
+(defn partition-nemesis
+  "A nemesis which partitions the network into various shapes. Responds to
+  {:f :start-partition-one}, {:f :start-partition-bridge}, etc. by healing
+  the network and then creating a fresh partition of that shape, and to {:f
+  :stop-partition} by healing the network."
+  []
+  (reify n/Nemesis
+    (setup! [this test]
+      (jnet/heal! (:net test) test)
+      this)
+
+    (invoke! [this test op]
+      (if (= :stop-partition (:f op))
+        (do (jnet/heal! (:net test) test)
+            (assoc op :value :network-healed))
+        (let [shape  (start-partition-fs (:f op))
+              grudge (grudge (:nodes test) shape)]
+          (jnet/heal! (:net test) test)
+          (partition! test grudge)
+          (assoc op :value [shape grudge]))))
+
+    (teardown! [this test]
+      (jnet/heal! (:net test) test))
+
+    n/Reflection
+    (fs [this]
+      (conj (set (keys start-partition-fs)) :stop-partition))))
+
+(defn partition-package
+  "A nemesis package for network partitions. Options are as for package;
+  (:shapes (:partition opts)) is a collection of partition shapes to choose
+  from. Each shape gets its own band in the perf plots."
+  [opts]
+  (let [needed? (contains? (:faults opts) :partition)
+        shapes  (:shapes (:partition opts) default-partition-shapes)
+        start   (fn start [_ _]
+                  {:type :info
+                   :f    (start-partition-f (r/rand-nth shapes))})
+        stop    {:type :info, :f :stop-partition, :value nil}
+        gen     (->> (gen/flip-flop start (repeat stop))
+                     (mgen/stagger (:interval opts)))]
+    {:generator       (when needed? gen)
+     :final-generator (when needed? stop)
+     :nemesis         (partition-nemesis)
+     :perf            (->> shapes
+                           (map (fn [shape]
+                                  {:name  (str "partition " (name shape))
+                                   :start #{(start-partition-f shape)}
+                                   :stop  #{:stop-partition}
+                                   :color (partition-shapes shape)}))
+                           set)}))
+
+(def net-faults
+  "Faults we inject by turning some dial on the network up, then back down. A
+  map of fault names to maps of:
+
+    :start    A function (f jepsen-net test value) which turns the fault on
+    :stop     A function (f jepsen-net test) which turns it off again
+    :param    The key in this fault's nemesis options giving the value to
+              start with, e.g. :p
+    :default  The default for that value
+    :value    An optional function (f test v) which turns the configured value
+              v into the value for each start operation, e.g. picking nodes
+    :color    The color for this fault in perf plots"
+  {:duplicate {:start   net/duplicate!
+               :stop    net/stop-duplicating!
+               :param   :p
+               :default 0.5
+               :color   "#A0C8E9"}
+   :slow      {:start   net/slow-by!
+               :stop    (fn [net test] (net/slow-by! net test 1))
+               :param   :factor
+               :default 10
+               :color   "#A0A6E9"}
+   :flaky     {:start   net/lose!
+               :stop    (fn [net test] (net/lose! net test 0))
+               :param   :p
+               :default 0.5
+               :color   "#E9A0D3"}
+   :corrupt   {:start   net/corrupt!
+               :stop    net/stop-corrupting!
+               :param   :p
+               :default 0.1
+               :color   "#E9C3A0"}
+   :equivocate {:start   net/equivocate!
+                :stop    net/stop-equivocating!
+                :param   :count
+                :default 1
+                :value   (fn [test n]
+                           (->> (:nodes test) r/shuffle (take n) vec))
+                :color   "#E9DCA0"}})
+
+(defn net-fault-fs
+  "The [start-f stop-f] for a network fault, e.g. [:start-slow :stop-slow]."
+  [fault]
+  [(keyword (str "start-" (name fault)))
+   (keyword (str "stop-" (name fault)))])
+
+(defn net-fault-nemesis
+  "A nemesis for one of the net-faults. Responds to {:f :start-slow, :value
+  10} by turning the fault on with that value, and {:f :stop-slow} by turning
+  it off."
+  [fault]
+  (let [{:keys [start stop]} (net-faults fault)
+        [start-f stop-f]     (net-fault-fs fault)]
+    (reify n/Nemesis
+      (setup! [this test]
+        this)
+
+      (invoke! [this test op]
+        (condp = (:f op)
+          start-f (do (start (:net test) test (:value op))
+                      (assoc op :value [fault (:value op)]))
+          stop-f  (do (stop (:net test) test)
+                      (assoc op :value :stopped))))
+
+      (teardown! [this test]
+        (stop (:net test) test))
+
+      n/Reflection
+      (fs [this]
+        #{start-f stop-f}))))
+
+(defn net-fault-package
+  "A nemesis package for one of the net-faults. Options are as for package;
+  the fault's own options (e.g. (:slow opts)) give its severity."
+  [fault opts]
+  (let [{:keys [param default color]} (net-faults fault)
+        [start-f stop-f] (net-fault-fs fault)
+        needed? (contains? (:faults opts) fault)
+        value   (get (get opts fault) param default)
+        value-f (:value (net-faults fault))
+        start   (if value-f
+                  (fn start [test ctx]
+                    {:type :info, :f start-f, :value (value-f test value)})
+                  {:type :info, :f start-f, :value value})
+        stop    {:type :info, :f stop-f, :value nil}
+        gen     (->> (gen/flip-flop (repeat start) (repeat stop))
+                     (mgen/stagger (:interval opts)))]
+    {:generator       (when needed? gen)
+     :final-generator (when needed? stop)
+     :nemesis         (net-fault-nemesis fault)
+     :perf            #{{:name  (name fault)
+                         :start #{start-f}
+                         :stop  #{stop-f}
+                         :color color}}}))
+
+(def default-slow-node-period
+  "How long, in ms, is each of a slow node's run/pause cycles?"
+  100)
+
+(defn slow-node-nemesis
+  "Makes nodes sluggish, rather than dead: a gray failure. Responds to {:f
+  :start-slow-node, :value [node ...]} by duty-cycling those nodes' processes
+  so they run only (:duty opts) of every (:period opts) ms, and to {:f
+  :stop-slow-node} by letting every node run freely again."
+  [db {:keys [duty period]}]
+  (let [slowed (atom #{})
+        stop!  (fn [test]
+                 (doseq [node @slowed]
+                   (mdb/unthrottle! db test node))
+                 (reset! slowed #{}))]
+    (reify n/Nemesis
+      (setup! [this test]
+        this)
+
+      (invoke! [this test op]
+        (case (:f op)
+          :start-slow-node (do (stop! test)
+                               (doseq [node (:value op)]
+                                 (mdb/throttle! db test node duty period)
+                                 (swap! slowed conj node))
+                               (assoc op :value [:slowed (:value op) duty]))
+          :stop-slow-node  (do (stop! test)
+                               (assoc op :value :stopped))))
+
+      (teardown! [this test]
+        (stop! test))
+
+      n/Reflection
+      (fs [this]
+        #{:start-slow-node :stop-slow-node}))))
+
+(defn slow-node-package
+  "A nemesis package which slows down a single random node at a time.
+  (:slow-node opts) is a map of :duty, the fraction of time the node gets to
+  run, and optionally :period, in ms."
+  [opts]
+  (let [needed? (contains? (:faults opts) :slow-node)
+        {:keys [duty period]} (:slow-node opts)
+        start   (fn start [test ctx]
+                  {:type  :info
+                   :f     :start-slow-node
+                   :value [(r/rand-nth (:nodes test))]})
+        stop    {:type :info, :f :stop-slow-node, :value nil}]
+    {:generator       (when needed?
+                        (->> (gen/flip-flop start (repeat stop))
+                             (mgen/stagger (:interval opts))))
+     :final-generator (when needed? stop)
+     :nemesis         (slow-node-nemesis
+                        (:db opts)
+                        {:duty   (or duty 0.1)
+                         :period (or period default-slow-node-period)})
+     :perf            #{{:name  "slow node"
+                         :start #{:start-slow-node}
+                         :stop  #{:stop-slow-node}
+                         :color "#B8A0E9"}}}))
+
+(defn amnesia-nemesis
+  "Responds to {:f :amnesia, :value [node ...]} by killing each node, wiping
+  its durable state, and restarting it."
+  [db]
+  (reify n/Nemesis
+    (setup! [this test]
+      this)
+
+    (invoke! [this test op]
+      (assoc op :value
+             (->> (:value op)
+                  (mapv (fn [node]
+                          (db/kill! db test node)
+                          (mdb/wipe! db test node)
+                          (db/start! db test node)
+                          [node :wiped])))))
+
+    (teardown! [this test])
+
+    n/Reflection
+    (fs [this]
+      #{:amnesia})))
+
+(defn amnesia-package
+  "A nemesis package which periodically makes a random node forget
+  everything it ever wrote to disk."
+  [opts]
+  (let [needed? (contains? (:faults opts) :amnesia)
+        gen     (->> (fn [test ctx]
+                       {:type  :info
+                        :f     :amnesia
+                        :value [(r/rand-nth (:nodes test))]})
+                     (mgen/stagger (:interval opts)))]
+    {:generator (when needed? gen)
+     :nemesis   (amnesia-nemesis (:db opts))
+     :perf      #{{:name  "amnesia"
+                   :fs    #{:amnesia}
+                   :color "#C0A0E9"}}}))
+
+(defn upgrade-nemesis
+  "Performs a rolling upgrade. Responds to {:f :upgrade} by restarting the next
+  node, in test order, which hasn't yet been upgraded, running the given
+  binary. The completed op's value records the node and its old and new
+  binaries."
+  [db bin]
+  (let [upgraded (atom #{})]
+    (reify n/Nemesis
+      (setup! [this test]
+        this)
+
+      (invoke! [this test op]
+        (if-let [node (first (remove @upgraded (:nodes test)))]
+          (do (swap! upgraded conj node)
+              (assoc op :value (mdb/upgrade! db test node bin)))
+          (assoc op :value :all-upgraded)))
+
+      (teardown! [this test])
+
+      n/Reflection
+      (fs [this]
+        #{:upgrade}))))
+
+(defn upgrade-package
+  "A nemesis package which upgrades nodes, one at a time, to (:bin (:upgrade
+  opts))."
+  [opts]
+  (let [bin (:bin (:upgrade opts))]
+    (when (and bin (contains? (:faults opts) :upgrade))
+      {:generator (->> (repeat {:type :info, :f :upgrade, :value nil})
+                       (take (count (:nodes opts)))
+                       (mgen/stagger (:interval opts)))
+       :nemesis   (upgrade-nemesis (:db opts) bin)
+       :perf      #{{:name  "upgrade"
+                     :fs    #{:upgrade}
+                     :color "#A0E9A6"}}})))
+
+(defn clock-nemesis
+  "Skews the clocks of a clock service. Responds to ops like:
+
+    {:f :bump-clock,   :value {node delta-ms}}
+    {:f :drift-clock,  :value {node rate}}
+    {:f :strobe-clock, :value {node {:delta ms, :period ms, :duration ms}}}
+    {:f :reset-clock,  :value [node ...]}
+
+  Like Jepsen's clock nemesis, completed ops carry the resulting
+  :clock-offsets of every skewed node, so skews show up in the history."
+  [clock]
+  (reify n/Nemesis
+    (setup! [this test]
+      this)
+
+    (invoke! [this test op]
+      (case (:f op)
+        :bump-clock   (doseq [[node delta] (:value op)]
+                        (service/bump-clock! clock node delta))
+        :drift-clock  (doseq [[node rate] (:value op)]
+                        (service/drift-clock! clock node rate))
+        :strobe-clock (doseq [[node {:keys [delta period duration]}]
+                              (:value op)]
+                        (service/strobe-clock! clock node delta period
+                                               duration))
+        :reset-clock  (doseq [node (:value op)]
+                        (service/reset-clock! clock node)))
+      (assoc op :clock-offsets (service/clock-offsets clock)))
+
+    (teardown! [this test]
+      (doseq [node (:nodes test)]
+        (service/reset-clock! clock node)))
+
+    n/Reflection
+    (fs [this]
+      #{:bump-clock :drift-clock :strobe-clock :reset-clock})))
+
+(defn clock-gen
+  "A generator of clock skew operations, given the largest skew to apply, in
+  ms."
+  [max-skew]
+  (let [some-nodes (fn [test]
+                     (let [nodes (:nodes test)]
+                       (take (inc (r/rand-int (count nodes)))
+                             (r/shuffle nodes))))
+        skew       (fn [] (- (r/rand-int (* 2 max-skew)) max-skew))]
+    (fn [test ctx]
+      (case (r/rand-nth [:bump :drift :strobe :reset])
+        :bump   {:type  :info
+                 :f     :bump-clock
+                 :value (->> (some-nodes test)
+                             (map (fn [node] [node (skew)]))
+                             (into (sorted-map)))}
+        :drift  {:type  :info
+                 :f     :drift-clock
+                 :value (->> (some-nodes test)
+                             (map (fn [node]
+                                    [node (r/rand-nth [0.5 0.9 1.1 2])]))
+                             (into (sorted-map)))}
+        :strobe {:type  :info
+                 :f     :strobe-clock
+                 :value (->> (some-nodes test)
+                             (map (fn [node]
+                                    [node {:delta    (skew)
+                                           :period   (inc (r/rand-int 1000))
+                                           :duration (r/rand-int 10000)}]))
+                             (into (sorted-map)))}
+        :reset  {:type  :info
+                 :f     :reset-clock
+                 :value (vec (some-nodes test))}))))
+
+(defn clock-package
+  "A nemesis package which skews nodes' clocks, as seen through the time
+  service. Options are as for package; (:clock opts) is a map of :clock, the
+  clock service, and :max-skew, in ms."
+  [opts]
+  (let [needed?  (contains? (:faults opts) :clock)
+        {:keys [clock max-skew]} (:clock opts)
+        reset    (fn [test ctx]
+                   {:type :info, :f :reset-clock, :value (vec (:nodes test))})]
+    (when clock
+      {:generator       (when needed?
+                          (mgen/stagger (:interval opts)
+                                        (clock-gen (or max-skew 10000))))
+       :final-generator (when needed? reset)
+       :nemesis         (clock-nemesis clock)
+       :perf            #{{:name  "clock"
+                           :fs    #{:bump-clock :drift-clock :strobe-clock
+                                    :reset-clock}
+                           :color "#A0E9DB"}}})))
+
+(defrecord Seeded [rng nemesis]
+  n/Nemesis
+  (setup! [this test]
+    (Seeded. rng (r/with-rng rng (n/setup! nemesis test))))
+
+  (invoke! [this test op]
+    (r/with-rng rng (n/invoke! nemesis test op)))
+
+  (teardown! [this test]
+    (r/with-rng rng (n/teardown! nemesis test)))
+
+  n/Reflection
+  (fs [this]
+    (n/fs nemesis)))
+
+(defn seeded
+  "Wraps a nemesis so that its random choices (e.g. which node to kill) come
+  from their own stream, derived from the seed. Only the nemesis worker
+  invokes it, so given the same operations, it makes the same choices."
+  [nemesis]
+  (Seeded. (r/rng :nemesis) nemesis))
+
 (defn package
   "A full nemesis package. Options are those for
-  jepsen.nemesis.combined/nemesis-package."
+  jepsen.nemesis.combined/nemesis-package, plus:
+
+    :partition    {:shapes [partition-shape ...]}
+    :duplicate    {:p probability-of-duplicating-each-message}
+    :slow         {:factor latency-multiplier}
+    :flaky        {:p probability-of-losing-each-message}
+    :slow-node    {:duty fraction-of-time-running, :period ms}
+
+  :kill and :pause faults come from jepsen.nemesis.combined/db-package.
+  :amnesia kills a node, wipes its durable state, and restarts it.
+  :clock skews the clock service given as {:clock clock, :max-skew ms}.
+  :upgrade restarts each of (:nodes opts), in turn, with {:bin new-binary}.
+  :membership adds and removes nodes; see maelstrom.membership/package."
   [opts]
   (nc/compose-packages
-    [(nc/partition-package opts)
-     (nc/db-package opts)]))
+    (concat [(partition-package opts)]
+            (map #(net-fault-package % opts) (keys net-faults))
+            (remove nil? [(slow-node-package opts)
+                          (amnesia-package opts)
+                          (clock-package opts)
+                          (upgrade-package opts)
+                          (membership/package opts)
+                          (nc/db-package opts)]))))
@@ -1,21 +1,51 @@
 (ns maelstrom.net
-  "A simulated, mutable unordered network, supporting randomized delivery,
-  selective packet loss, and long-lasting partitions."
-  (:require [clojure.tools.logging :refer [info warn]]
+  "A simulated, mutable network, supporting randomized delivery, selective
+  packet loss, message duplication, and long-lasting partitions.
+
+  By default the network is unordered: messages on the same link may be
+  reordered whenever latency varies. In FIFO mode, each (src, dest) link
+  behaves like a TCP connection: every message is still delayed, but never
+  overtakes an earlier message on the same link.
+
+  Nodes (but not clients or services) may also be subject to a bandwidth
+  limit, which delays messages while their sender (or link) is busy
+  transmitting earlier ones, and to a bounded inbox: messages which arrive
+  while a node already has a full inbox of unread messages are dropped.
+
+  Finally, the network can tamper with messages between nodes: corrupting
+  bodies at random, or rewriting everything a Byzantine node sends so that
+  each recipient hears a different story. See maelstrom.net.tamper.
+
+  Normally, messages are delivered in real time: a message with 10 ms of
+  latency becomes visible to its recipient 10 ms after it was sent. In
+  virtual-time mode, deadlines are instead measured against a virtual clock,
+  and a single scheduler thread delivers messages one at a time, in deadline
+  order, advancing the clock as it goes. With a fixed seed, the network then
+  adds no nondeterminism of its own: a message's latency depends only on its
+  sender's seeded stream, not on scheduler jitter. This costs throughput:
+  after each delivery, the scheduler waits (for at least virtual-settle-ms)
+  for the recipient to reply, unless the recipient is down. Clients, the
+  nemesis, and nodes still run in real time, though; see maelstrom.random."
+  (:require [cheshire.core :as json]
+            [clojure.tools.logging :refer [info warn]]
             [jepsen [core :as jepsen]
                     [net :as net]
-                    [os :as os]]
-            [maelstrom [util :as u]]
-            [maelstrom.net [message :as msg]
-                           [journal :as j]]
+                    [os :as os]
+                    [util :as util]]
+            [maelstrom [random :as r]
+                       [util :as u]]
+            [maelstrom.net [crash :as crash]
+                           [message :as msg]
+                           [journal :as j]
+                           [rules :as rules]
+                           [tamper :as tamper]]
             [slingshot.slingshot :refer [try+ throw+]]
             [schema.core :as s]
             [incanter.distributions :as dist
              :refer [Distribution
-                     draw
-                     exponential-distribution
-                     integer-distribution]])
-  (:import (java.util.concurrent PriorityBlockingQueue
+                     draw]])
+  (:import (java.util Random)
+           (java.util.concurrent PriorityBlockingQueue
                                  TimeUnit)))
 
 ; Message validation
@@ -35,8 +65,19 @@
   "Returns schema errors on the given message, if any."
   (s/checker Message))
 
-(defn latency-compare [a b]
-  (compare (:deadline a) (:deadline b)))
+(defn latency-compare
+  "Orders envelopes by deadline. Ties are broken by source, then message ID,
+  so that the order doesn't depend on which queue a message landed in first."
+  [a b]
+  (let [c (compare (:deadline a) (:deadline b))]
+    (if-not (zero? c)
+      c
+      (let [ma (:message a)
+            mb (:message b)
+            c  (compare (:src ma) (:src mb))]
+        (if-not (zero? c)
+          c
+          (compare (:id ma) (:id mb)))))))
 
 (defrecord ConstantDistribution [x]
   Distribution
@@ -47,6 +88,98 @@
   [x]
   (ConstantDistribution. x))
 
+(defrecord UniformDistribution [lower upper]
+  Distribution
+  (draw [this] (+ lower (r/rand-int (- upper lower)))))
+
+(defn uniform-dist
+  "A uniform distribution of integers in [lower, upper)."
+  [lower upper]
+  (UniformDistribution. lower upper))
+
+(defrecord ExponentialDistribution [mean]
+  Distribution
+  (draw [this] (* mean (- (Math/log (- 1.0 (r/rand)))))))
+
+(defn exponential-dist
+  "An exponential distribution with the given mean."
+  [mean]
+  (ExponentialDistribution. mean))
+
+(defrecord LogNormalDistribution [mu sigma]
+  Distribution
+  (draw [this] (Math/exp (+ mu (* sigma (r/gaussian))))))
+
+(def lognormal-sigma
+  "The shape of our lognormal distributions. Larger is a longer tail."
+  1.0)
+
+(defn lognormal-dist
+  "A lognormal distribution with the given mean, and shape sigma."
+  ([mean]
+   (lognormal-dist mean lognormal-sigma))
+  ([mean sigma]
+   ; The mean of a lognormal is e^(mu + sigma^2/2)
+   (LogNormalDistribution. (- (Math/log (max mean 1e-9)) (/ (* sigma sigma) 2))
+                           sigma)))
+
+(defrecord ParetoDistribution [scale shape]
+  Distribution
+  (draw [this] (/ scale (Math/pow (- 1.0 (r/rand)) (/ shape)))))
+
+(def pareto-shape
+  "The shape (alpha) of our Pareto distributions. 2 gives a finite mean, but
+  infinite variance: most messages are quick, and a few are very slow."
+  2.0)
+
+(defn pareto-dist
+  "A Pareto distribution with the given mean and shape, which must be greater
+  than 1."
+  ([mean]
+   (pareto-dist mean pareto-shape))
+  ([mean shape]
+   ; The mean of a Pareto is shape * scale / (shape - 1)
+   (ParetoDistribution. (/ (* mean (dec shape)) shape) shape)))
+
+(defrecord BimodalDistribution [fast slow p-slow]
+  Distribution
+  (draw [this]
+    (if (< (r/rand) p-slow)
+      (dist/draw slow)
+      (dist/draw fast))))
+
+(def bimodal-p-slow
+  "In a bimodal distribution, what fraction of messages take the slow path?"
+  0.1)
+
+(def bimodal-slow-factor
+  "In a bimodal distribution, how much slower is the slow path than the fast
+  one?"
+  10)
+
+(defn bimodal-dist
+  "A bimodal distribution with the given mean: most messages are fast, but
+  some fraction (bimodal-p-slow) are slower by bimodal-slow-factor, as if
+  they'd taken a retransmit or a detour. Each mode is uniform within +/- 50%
+  of its own mean."
+  [mean]
+  (let [fast (/ mean (+ (- 1 bimodal-p-slow)
+                        (* bimodal-p-slow bimodal-slow-factor)))
+        slow (* fast bimodal-slow-factor)
+        mode (fn [m] (uniform-dist (long (* 0.5 m))
+                                   (inc (long (* 1.5 m)))))]
+    (BimodalDistribution. (mode fast) (mode slow) bimodal-p-slow)))
+
+(defrecord EmpiricalDistribution [samples]
+  Distribution
+  (draw [this] (r/rand-nth samples)))
+
+(defn empirical-dist
+  "A distribution which draws uniformly from a vector of recorded samples."
+  [samples]
+  (assert (seq samples) "An empirical distribution needs samples")
+  (EmpiricalDistribution. (vec samples)))
+
 (defrecord ScaledDistribution [d scale]
   Distribution
   (draw [this] (* scale (dist/draw d))))
@@ -57,37 +190,160 @@
   (ScaledDistribution. d scale))
 
 (defn unscale-dist
-  "Unwrap a ScaledDistribution."
+  "Unwrap a ScaledDistribution. Other distributions are returned unchanged."
   [sd]
-  (:d sd))
+  (if (instance? ScaledDistribution sd)
+    (:d sd)
+    sd))
+
+(defn rescale-dist
+  "Replaces any existing scaling of d with the given scale."
+  [d scale]
+  (let [d (unscale-dist d)]
+    (if (== 1 scale)
+      d
+      (scale-dist d scale))))
+
+(def latency-dists
+  "The kinds of latency distributions we support."
+  #{:constant :uniform :exponential :lognormal :pareto :bimodal :empirical})
 
 (defn latency-dist
   "Takes options:
 
     :mean   The mean latency
-    :dist   The shape of the distribution of latencies injected
+    :dist   The shape of the distribution of latencies injected; one of
+            latency-dists
+    :trace  For :empirical distributions, a collection of one-way latencies,
+            in ms, to sample from. :mean is ignored.
 
   and yields an Incanter distribution, used to generate latencies for each
-  message."
-  [{:keys [dist mean]}]
+  message. Distributions draw from maelstrom.random, so they're seeded by
+  --seed."
+  [{:keys [dist mean trace]}]
   (case dist
     :constant     (constant-dist mean)
-    :uniform      (integer-distribution 0 (* 2 mean))
-    :exponential  (exponential-distribution (/ mean))))
+    :uniform      (uniform-dist 0 (* 2 mean))
+    :exponential  (exponential-dist mean)
+    :lognormal    (lognormal-dist mean)
+    :pareto       (pareto-dist mean)
+    :bimodal      (bimodal-dist mean)
+    :empirical    (empirical-dist trace)))
+
+(defn map-dists
+  "Applies (f dist & args) to every distribution in a map of keys to
+  distributions."
+  [dists f & args]
+  (->> dists
+       (map (fn [[k d]] [k (apply f d args)]))
+       (into {})))
+
+(defn link-latency-dists
+  "Takes a map of [src-region dest-region] pairs to latency specification maps
+  (see latency-dist), and yields a map of those pairs to distributions."
+  [region-latencies]
+  (map-dists region-latencies latency-dist))
+
+(def virtual-settle-ms
+  "In virtual-time mode, how long must the network go without any new
+  messages before we consider it settled, and deliver the next message?"
+  5)
+
+(def virtual-max-wait-ms
+  "In virtual-time mode, the longest we'll wait for the network to settle
+  after a delivery. A node which has crashed or hung might never take its
+  message; we don't want to stall the whole network on it."
+  1000)
 
 (defn net
-  "Construct a new network. Takes a latency specification map (see
-  latency-dist).
+  "Construct a new network. Takes an options map:
+
+      :latency        A latency specification map (see latency-dist)
+      :client-latency Optionally, a latency specification map for messages to
+                      and from clients. If omitted, clients have no latency.
+      :log-send?      Whether to log messages as they're sent
+      :log-recv?      Whether to log messages as they're received
+      :virtual-time?  If true, deliver messages in virtual time
+      :regions        A map of node IDs to region names
+      :region-latencies  A map of [src-region dest-region] pairs to latency
+                         specification maps, for links between those regions.
+                         Links not in this map use :latency.
+      :p-duplicate    The probability that a message between servers is
+                      delivered twice
+      :ordering       Either :unordered (the default) or :fifo
+      :bandwidth      Optionally, a map of:
+                        :rate   How many units per second a node may send
+                        :unit   :msgs, or :bytes (of JSON-encoded bodies)
+                        :scope  :node, where the limit applies to all of a
+                                node's outbound traffic together, or :link,
+                                where it applies to each destination
+                                separately
+      :inbox-capacity Optionally, roughly how many unread messages a node's
+                      inbox can hold; see drop-tail!
+      :rules          A vector of rules for specific messages; see
+                      maelstrom.net.rules
+      :crash-points   A vector of crash points; see maelstrom.net.crash
+
+  The network is an atom of a map with:
 
       :queues      A map of receiver node ids to PriorityQueues
       :journal     A mutable log for network messages
       :p-loss      The probability of any given message being lost
+      :p-dup       The probability that a server-to-server message is
+                   delivered twice. Duplicates are delivered with their own,
+                   independently drawn latency.
+      :base-p-dup  The :p-dup the network was constructed with; restored when
+                   the duplicate fault stops.
+      :p-corrupt   The probability that a message between nodes has its body
+                   corrupted
+      :equivocators  A set of nodes whose outbound messages are rewritten, so
+                     each recipient sees conflicting content
       :partitions  A map of receivers to collections of sources. If a
                    source/receiver pair exists, receiver will drop packets
                    from source.
       :latency-dist   An incanter distribution used to generate latencies
-                      for messages"
-  [latency log-send? log-recv?]
+                      for messages
+      :client-latency-dist  A distribution for messages involving clients, or
+                            nil for zero latency
+      :regions     A map of node IDs to region names
+      :link-latency-dists  A map of [src-region dest-region] pairs to
+                           distributions which override :latency-dist
+      :ordering    :unordered or :fifo
+      :link-deadlines  In FIFO mode, an atom of a map of [src dest] pairs to
+                       the latest deadline assigned to a message on that link
+      :processes   A set of node IDs backed by real processes, as opposed to
+                   clients and services. Only these are subject to
+                   :bandwidth, :inbox-capacity, and tampering.
+      :bandwidth   As for the options, or nil
+      :busy-until  An atom of a map of nodes (or [src dest] links) to the
+                   time they finish transmitting their last message
+      :inbox-capacity  As for the options, or nil
+      :rules       As for the options
+      :start-nanos The network clock's time when the test began, which rule
+                   windows are relative to
+      :crash-points  As for the options
+      :crash-counts  An atom of a map of crash point names to how many
+                     messages have matched them
+      :crashed     A set of nodes which hit a crash point, and are cut off
+                   from the network until they're added again
+      :crash-handler  A function (f node crash-point), called when a crash
+                      point fires; see on-crash!
+      :departed    A set of nodes which left the cluster; see depart!
+      :down        A set of nodes which are killed or paused, and won't read
+                   their inboxes for a while; see down!
+      :rngs        An atom of a map of source nodes to Randoms, used for
+                   that source's latency and loss draws
+      :vclock      An atom of the current virtual time, in nanoseconds
+      :epoch-millis  The wall-clock time, in ms, when we built the network;
+                     virtual time counts up from here
+      :vqueue      In virtual-time mode, a PriorityQueue of all messages
+                   not yet handed to their receivers
+      :last-send   An atom of the real nanoTime of the last send; used to
+                   tell when the network has settled
+      :scheduler   In virtual-time mode, the running scheduler, if any"
+  [{:keys [latency client-latency log-send? log-recv? virtual-time? regions
+           region-latencies p-duplicate ordering bandwidth inbox-capacity
+           rules crash-points]}]
   (atom {:queues          {}
          ; This will be filled in by the OS adapter--we need this to manage the
          ; disk file open/close lifecycle, and because we'll need a test map
@@ -96,11 +352,103 @@
          :log-send?       log-send?
          :log-recv?       log-recv?
          :latency-dist    (latency-dist latency)
+         :client-latency-dist (when client-latency
+                                (latency-dist client-latency))
+         :regions         (or regions {})
+         :link-latency-dists (link-latency-dists region-latencies)
          :p-loss          0
+         :p-dup           (or p-duplicate 0)
+         :base-p-dup      (or p-duplicate 0)
+         :p-corrupt       0
+         :equivocators    #{}
          :partitions      {}
+         :ordering        (or ordering :unordered)
+         :link-deadlines  (atom {})
+         :processes       #{}
+         :bandwidth       bandwidth
+         :busy-until      (atom {})
+         :inbox-capacity  inbox-capacity
+         :rules           (vec rules)
+         :start-nanos     (if virtual-time? 0 (System/nanoTime))
+         :crash-points    (vec crash-points)
+         :crash-counts    (atom {})
+         :crashed         #{}
+         :crash-handler   nil
+         :departed        #{}
+         :down            #{}
+         :rngs            (atom {})
+         :virtual-time?   (boolean virtual-time?)
+         :vclock          (atom 0)
+         :epoch-millis    (System/currentTimeMillis)
+         :vqueue          (PriorityBlockingQueue. 11 latency-compare)
+         :last-send       (atom (System/nanoTime))
+         :scheduler       nil
          :next-client-id  -1
          :next-message-id (atom -1)}))
 
+(defn now-nanos
+  "The current time on the given deref'ed network's clock, in nanoseconds.
+  This is System/nanoTime, unless we're in virtual-time mode."
+  [net]
+  (if (:virtual-time? net)
+    @(:vclock net)
+    (System/nanoTime)))
+
+(defn now-millis
+  "The current time on the given deref'ed network's clock, in milliseconds
+  since the epoch. This is System/currentTimeMillis, unless we're in
+  virtual-time mode."
+  [net]
+  (if (:virtual-time? net)
+    (+ (:epoch-millis net) (quot @(:vclock net) 1000000))
+    (System/currentTimeMillis)))
+
+(defn ^Random rng-for
+  "Returns the Random we use for draws concerning messages from the given
+  source. Each source gets its own stream derived from the test seed, so a
+  node's draws don't depend on how its sends interleave with other nodes'."
+  [net src]
+  (let [rngs (:rngs @net)]
+    (or (get @rngs src)
+        (-> (swap! rngs (fn [rngs]
+                          (if (contains? rngs src)
+                            rngs
+                            (assoc rngs src (r/rng src)))))
+            (get src)))))
+
+(defprotocol Degrade
+  "Jepsen's Net protocol can slow the network down and make it flaky, but
+  offers no way to say by how much. We offer our own."
+  (slow-by! [net test factor]
+            "Multiplies every latency by factor, relative to normal. A factor
+            of 1 restores normal latencies.")
+  (lose! [net test p]
+         "Drops each message with probability p. 0 restores normal
+         delivery."))
+
+(defprotocol Duplicate
+  "Jepsen's Net protocol has no notion of duplicated messages, so we offer our
+  own. The nemesis calls these on the test's :net."
+  (duplicate! [net test p]
+              "Begins delivering server-to-server messages twice, with
+              probability p.")
+  (stop-duplicating! [net test]
+                     "Returns message duplication to its normal level."))
+
+(defprotocol Tamper
+  "Faults which alter messages in transit, for testing Byzantine
+  fault-tolerant protocols."
+  (corrupt! [net test p]
+            "Begins corrupting the bodies of messages between nodes, with
+            probability p.")
+  (stop-corrupting! [net test]
+                    "Stops corrupting messages.")
+  (equivocate! [net test nodes]
+               "Rewrites everything the given nodes send, so that different
+               recipients see conflicting content.")
+  (stop-equivocating! [net test]
+                      "Stops rewriting messages."))
+
 (defn jepsen-net
   "A jepsen.net/Net which controls this network."
   [net]
@@ -111,14 +459,98 @@
     (heal! [_ test]
       (swap! net assoc :partitions {}))
 
-    (slow! [_ test]
-      (swap! net update :latency-dist scale-dist 10))
-
-    (fast! [_ test]
-      (swap! net update :latency-dist unscale-dist))
-
-    (flaky! [_ test]
-      (swap! net assoc :p-loss 0.5))))
+    (slow! [this test]
+      (slow-by! this test 10))
+
+    (fast! [this test]
+      (slow-by! this test 1)
+      (lose! this test 0))
+
+    (flaky! [this test]
+      (lose! this test 0.5))
+
+    Degrade
+    (slow-by! [_ test factor]
+      (swap! net (fn [net]
+                   (-> net
+                       (update :latency-dist rescale-dist factor)
+                       (update :link-latency-dists
+                               map-dists rescale-dist factor)))))
+
+    (lose! [_ test p]
+      (swap! net assoc :p-loss p))
+
+    Duplicate
+    (duplicate! [_ test p]
+      (swap! net assoc :p-dup p))
+
+    (stop-duplicating! [_ test]
+      (swap! net (fn [net] (assoc net :p-dup (:base-p-dup net)))))
+
+    Tamper
+    (corrupt! [_ test p]
+      (swap! net assoc :p-corrupt p))
+
+    (stop-corrupting! [_ test]
+      (swap! net assoc :p-corrupt 0))
+
+    (equivocate! [_ test nodes]
+      (swap! net assoc :equivocators (set nodes)))
+
+    (stop-equivocating! [_ test]
+      (swap! net assoc :equivocators #{}))))
+
+(defn await-settled!
+  "Used by the virtual-time scheduler. Blocks until the given queue has been
+  drained, and nobody has sent a message for virtual-settle-ms, or until
+  virtual-max-wait-ms has elapsed."
+  [net running? ^PriorityBlockingQueue q]
+  (let [{:keys [last-send]} @net
+        settle   (* virtual-settle-ms 1000000)
+        give-up  (+ (System/nanoTime) (* virtual-max-wait-ms 1000000))]
+    (loop []
+      (let [now (System/nanoTime)]
+        (when (and @running?
+                   (< now give-up)
+                   (or (pos? (.size q))
+                       (< (- now @last-send) settle)))
+          (Thread/sleep 1)
+          (recur))))))
+
+(defn scheduler
+  "In virtual-time mode, spawns a single thread which moves messages from the
+  network's :vqueue to their receivers' queues one at a time, in deadline
+  order, advancing the virtual clock to each message's deadline. After each
+  delivery, we wait for the network to settle, so that whatever the receiver
+  sends in response is scheduled before we move on. Receivers which are down
+  or crashed won't respond, so we don't wait for them. Returns a map of
+  {:running? atom, :worker future}."
+  [net]
+  (let [running? (atom true)
+        {:keys [^PriorityBlockingQueue vqueue vclock]} @net]
+    {:running? running?
+     :worker
+     (future
+       (util/with-thread-name "maelstrom net scheduler"
+         (while @running?
+           (try+
+             (when-let [envelope (.poll vqueue 100 TimeUnit/MILLISECONDS)]
+               (swap! vclock max (:deadline envelope))
+               (let [{:keys [queues journal down crashed]} @net
+                     message (:message envelope)
+                     dest    (:dest message)]
+                 (if-let [q (get queues dest)]
+                   (do (.put q envelope)
+                       (when-not (or (contains? down dest)
+                                     (contains? crashed dest))
+                         (await-settled! net running? q)))
+                   ; The receiver has left the network; drop the message.
+                   (j/log-drop! journal message #{:departed}))))
+             (catch InterruptedException e
+               ; We're shutting down
+               nil)
+             (catch Exception e
+               (warn e "Error in virtual-time scheduler"))))))}))
 
 (defn jepsen-os
   "A jepsen.os/OS used to start and stop the network."
@@ -127,97 +559,418 @@
     (setup! [this test node]
       (when (= node (jepsen/primary test))
         (info "Starting Maelstrom network")
-        (swap! net assoc :journal (j/journal test))))
+        (swap! net (fn [n]
+                     (assoc n
+                            :journal     (j/journal test)
+                            :start-nanos (now-nanos n))))
+        (when (:virtual-time? @net)
+          (info "Starting virtual-time scheduler")
+          (swap! net assoc :scheduler (scheduler net)))))
 
     (teardown! [this test node]
       (when (= node (jepsen/primary test))
+        (when-let [{:keys [running? worker]} (:scheduler @net)]
+          (reset! running? false)
+          @worker
+          (swap! net assoc :scheduler nil))
         (when-let [j (:journal @net)]
           (info "Shutting down Maelstrom network")
           (j/close! j))))))
 
 (defn add-node!
-  "Adds a node to the network."
-  [net node-id]
-  (assert (string? node-id) (str "Node id " (pr-str node-id)
-                                 " must be a string"))
-  (swap! net assoc-in [:queues node-id]
-         (PriorityBlockingQueue. 11 latency-compare))
-  net)
+  "Adds a node to the network. Options:
+
+      :process?   If true, this node is backed by a real process, and is
+                  subject to the network's bandwidth and inbox limits, and
+                  to tampering."
+  ([net node-id]
+   (add-node! net node-id {}))
+  ([net node-id opts]
+   (assert (string? node-id) (str "Node id " (pr-str node-id)
+                                  " must be a string"))
+   (swap! net (fn [net]
+                (cond-> (-> net
+                            (assoc-in [:queues node-id]
+                                      (PriorityBlockingQueue.
+                                        11 latency-compare))
+                            (update :crashed disj node-id)
+                            (update :departed disj node-id)
+                            (update :down disj node-id))
+                  (:process? opts) (update :processes conj node-id))))
+   net))
+
+(defn involves-node?
+  "Does a key in one of our per-node or per-link maps (a node ID or a [src
+  dest] pair) involve the given node?"
+  [node-id k]
+  (if (vector? k)
+    (some #{node-id} k)
+    (= node-id k)))
 
 (defn remove-node!
   "Removes a node from the network."
   [net node-id]
-  (swap! net update :queues dissoc node-id)
+  (swap! net (fn [net]
+               (-> net
+                   (update :queues dissoc node-id)
+                   (update :processes disj node-id)
+                   (update :crashed disj node-id)
+                   (update :down disj node-id))))
+  (doseq [a [(:link-deadlines @net) (:busy-until @net)]]
+    (swap! a (fn [m]
+               (->> m
+                    (remove (comp (partial involves-node? node-id) key))
+                    (into {})))))
+  net)
+
+(defn down!
+  "Records that a node's process is killed or paused, so it won't read its
+  inbox until it's restarted (which adds it again) or resumed (see up!).
+  Messages to it are still delivered, but the virtual-time scheduler doesn't
+  wait for it to respond."
+  [net node-id]
+  (swap! net update :down conj node-id)
+  net)
+
+(defn up!
+  "Records that a node which was down (see down!) is running again."
+  [net node-id]
+  (swap! net update :down disj node-id)
+  net)
+
+(defn depart!
+  "Records that a node has left the cluster. Once it's removed from the
+  network, messages to it are dropped, and sending one throws
+  ::node-not-found, rather than failing as an invalid message. Adding the node
+  again clears this."
+  [net node-id]
+  (swap! net update :departed conj node-id)
   net)
 
+(defn node-not-found!
+  "Throws ::node-not-found for the given node ID."
+  [node]
+  (throw+ {:type      ::node-not-found
+           :name      :node-not-found
+           :code      1
+           :definite? true}
+          nil
+          (str "No such node in network: " (pr-str node))))
+
 (defn ^PriorityBlockingQueue queue-for
   "Returns the queue for a particular recipient node."
   [net node]
   (if-let [q (-> net deref :queues (get node))]
     q
-    (throw+ {:type      ::node-not-found
-             :name      :node-not-found
-             :code      1
-             :definite? true}
-            nil
-            (str "No such node in network: " (pr-str node)))))
+    (node-not-found! node)))
 
 (defn validate-msg
   "Checks to make sure a message is well-formed and deliverable on the given
-  deref'ed network. Returns msg if legal, otherwise throws."
+  deref'ed network. Returns msg if legal, otherwise throws. Messages to nodes
+  which have departed are legal, though undeliverable; see send!."
   [m net]
   (let [m (msg/validate m)
         queues (get net :queues)]
     (assert (get queues (:src m))
             (str "Invalid source for message " (pr-str m)))
-    (assert (get queues (:dest m))
+    (assert (or (get queues (:dest m))
+                (contains? (:departed net) (:dest m)))
             (str "Invalid dest for message " (pr-str m)))
     m))
 
+(defn latency-dist-for
+  "Which latency distribution applies to messages from src to dest, on the
+  given deref'ed network? If both nodes are in regions, and we have a
+  distribution for that pair of regions, uses it. Otherwise, uses the
+  network's default :latency-dist."
+  [net src dest]
+  (let [regions (:regions net)]
+    (get (:link-latency-dists net)
+         [(get regions src) (get regions dest)]
+         (:latency-dist net))))
+
 (defn ^Long latency-for
   "Computes a latency, in ms, for a given message. We want our clients to have
   effectively zero latency whenever possible--as if colocated with nodes.
-  Adding latency to them tends to *hide* consistency anomalies, so we avoid it.
-  Later we might want to add an option for a separate client latency
-  distribution, just for latency simulation purposes?"
+  Adding latency to them tends to *hide* consistency anomalies, so by default
+  we avoid it. For latency simulation purposes, the network may have a
+  separate :client-latency-dist, which applies to every message to or from a
+  client."
   [net message]
   (if (u/involves-client? message)
-    0
-    (long (draw (:latency-dist net)))))
+    (if-let [d (:client-latency-dist net)]
+      (long (draw d))
+      0)
+    (long (draw (latency-dist-for net (:src message) (:dest message))))))
+
+(defn message-size
+  "How big is a message, in the given bandwidth unit?"
+  [unit message]
+  (case unit
+    :msgs  1
+    :bytes (count (.getBytes ^String (json/generate-string (:body message))
+                             "UTF-8"))))
+
+(defn transmitted-at
+  "Takes a deref'ed network, a message, and the current time on the network's
+  clock. Returns the time at which the sender finishes transmitting the
+  message. Without a bandwidth limit, that's right now. With one, the sender
+  (or link) must first finish transmitting everything it sent earlier, then
+  spend size / rate seconds on this message; we reserve that time."
+  [net message now]
+  (let [{:keys [bandwidth processes busy-until]} net
+        src (:src message)]
+    (if (and bandwidth
+             (contains? processes src)
+             (not (u/involves-client? message)))
+      (let [{:keys [rate unit scope]} bandwidth
+            dt (long (* 1e9 (/ (message-size unit message) rate)))
+            k  (case scope
+                 :node src
+                 :link [src (:dest message)])]
+        (-> busy-until
+            (swap! update k (fn [busy]
+                              (+ dt (if busy (max busy now) now))))
+            (get k)))
+      now)))
+
+(defn deadline-for
+  "Draws a deadline, in nanoseconds on the network's clock, for a message to
+  be delivered. Takes a deref'ed network. In FIFO mode, the deadline is pushed
+  back, if necessary, so that it comes no earlier than any previous message on
+  the same link. Since envelopes with equal deadlines are ordered by source and
+  message ID, that's enough to preserve per-link order."
+  [net message]
+  (let [deadline (-> net
+                     (latency-for message)
+                     (* 1000000) ; ms -> ns
+                     (+ (transmitted-at net message (now-nanos net))))]
+    (if (= :fifo (:ordering net))
+      (let [link [(:src message) (:dest message)]]
+        (-> (:link-deadlines net)
+            (swap! update link (fn [prev]
+                                 (if prev
+                                   (max prev deadline)
+                                   deadline)))
+            (get link)))
+      deadline)))
+
+(defn tamper-with
+  "Takes a deref'ed network and a message on its way between two nodes, and
+  possibly tampers with it. Returns [message' tags], where tags is nil, or a
+  set like #{:corrupted :swap} or #{:equivocated} noting what we did."
+  [net message]
+  (let [{:keys [processes p-corrupt equivocators]} net
+        src  (:src message)
+        dest (:dest message)]
+    (if-not (and (contains? processes src) (contains? processes dest))
+      [message nil]
+      (let [; Byzantine nodes lie first...
+            [message tags]
+            (if (contains? equivocators src)
+              (let [i     (.indexOf (vec (u/sort-clients processes)) dest)
+                    body' (tamper/equivocate i (:body message))]
+                (if (= body' (:body message))
+                  [message nil]
+                  [(assoc message :body body') #{:equivocated}]))
+              [message nil])
+            ; ... and then the network may garble the lie.
+            [body' kind] (when (and (pos? p-corrupt)
+                                    (< (r/rand) p-corrupt))
+                           (tamper/corrupt (:body message)))]
+        (if kind
+          [(assoc message :body body') (into (or tags #{}) [:corrupted kind])]
+          [message tags])))))
+
+(defn envelope
+  "Prepares a message for delivery. Takes a deref'ed network, a message, and
+  an initial set of tags (or nil). Draws a deadline, possibly tampers with the
+  message, and returns an envelope of :deadline, :message, and :tags. Tags are
+  journaled when the message is received."
+  [net message tags]
+  (let [[message tamper-tags] (tamper-with net message)
+        tags (if tamper-tags
+               (into (or tags #{}) tamper-tags)
+               tags)]
+    {:deadline (deadline-for net message)
+     :message  message
+     :tags     tags}))
+
+(defn apply-rules
+  "Applies the network's rules to an envelope, journaling each application.
+  Takes a deref'ed network, and returns a collection of envelopes to enqueue:
+  none, if a rule dropped the message, or several, if rules duplicated it."
+  [net envelope]
+  (let [{:keys [rules journal start-nanos]} net]
+    (if (empty? rules)
+      [envelope]
+      (let [message (:message envelope)
+            t       (/ (- (now-nanos net) start-nanos) 1e9)]
+        (reduce
+          (fn [envs {:keys [action] :as rule}]
+            (if-not (rules/match? rule t message)
+              envs
+              (if (= :drop action)
+                (do (j/log-drop! journal message (rules/tags rule))
+                    (reduced []))
+                (do (j/log-rule! journal message (rules/tags rule))
+                    (case action
+                      :delay
+                      (let [dt (long (* 1e6 (:delay rule)))]
+                        (mapv #(update % :deadline + dt) envs))
+
+                      :hold
+                      (let [until (+ start-nanos
+                                     (long (* 1e9 (:before rule))))]
+                        (mapv #(update % :deadline max until) envs))
+
+                      :duplicate
+                      (into envs
+                            (map (fn [e]
+                                   (assoc e
+                                          :deadline (deadline-for net message)
+                                          :tags (conj (or (:tags e) #{})
+                                                      :duplicate))))
+                            envs))))))
+          [envelope]
+          rules)))))
+
+(defn on-crash!
+  "Sets the function (f node crash-point) the network calls when a crash
+  point fires. It's called on the node's own I/O threads, so it should kill
+  the node asynchronously."
+  [net f]
+  (swap! net assoc :crash-handler f))
+
+(defn crash!
+  "Checks whether a node sending (:send) or receiving (:recv) a message hits
+  one of the network's crash points. If so, cuts the node off from the
+  network, calls the crash handler, and returns the crash point. Returns nil
+  otherwise."
+  [net event node message]
+  (let [{:keys [crash-points crash-counts processes]} @net]
+    (when (and (seq crash-points) (contains? processes node))
+      (let [matches (filterv #(crash/match? % event node message)
+                             crash-points)
+            counts  (when (seq matches)
+                      (swap! crash-counts
+                             (fn [counts]
+                               (reduce #(update %1 (:name %2) (fnil inc 0))
+                                       counts
+                                       matches))))]
+        (when-let [point (->> matches
+                              (filter #(= (:nth % 1) (get counts (:name %))))
+                              first)]
+          (info "Node" node "hit crash point" (:name point))
+          (swap! net update :crashed conj node)
+          (when-let [handler (:crash-handler @net)]
+            (handler node point))
+          point)))))
+
+(defn enqueue!
+  "Puts an envelope (a map of :deadline and :message) on its way to its
+  recipient. In real-time mode, that's the recipient's queue. In virtual-time
+  mode, the scheduler's queue."
+  [net envelope]
+  (let [n @net]
+    (if (:virtual-time? n)
+      (do (.put ^PriorityBlockingQueue (:vqueue n) envelope)
+          (reset! (:last-send n) (System/nanoTime)))
+      (.put (queue-for net (:dest (:message envelope))) envelope))
+    net))
 
 (defn send!
   "Sends a message (either a map or Message) into the network. Message must
   contain :src and :dest keys, both node IDs. Generates an :id for the message.
-  Mutates and returns the network."
+  Mutates and returns the network.
+
+  With probability :p-dup, a message between servers is enqueued a second
+  time, with its own latency. Both copies share the same message ID; the
+  duplicate is journaled with a :duplicate tag when received. Each copy may be
+  tampered with independently; the journal records the message as sent, and
+  each copy as received.
+
+  Finally, the network's rules (see maelstrom.net.rules) may drop, delay,
+  duplicate, or hold each copy.
+
+  If the recipient has left the cluster, the message is journaled as
+  dropped, and we throw ::node-not-found."
   [net message]
-  (let [{:keys [log-send? p-loss journal next-message-id] :as n} @net
+  (let [{:keys [log-send? p-loss p-dup journal next-message-id] :as n} @net
         ; Assign a new message ID for our internal bookkeeping, and construct a
         ; Message object.
         message (-> (msg/message (swap! next-message-id inc)
                                  (:src message)
                                  (:dest message)
                                  (:body message))
-                    (validate-msg n))
-        deadline (-> n
-                     (latency-for message)
-                     (* 1000000) ; ms -> ns
-                     (+ (System/nanoTime)))]
-
-    ; Journal
-    (j/log-send! journal message)
-
-    ; Log
-    (when log-send? (info :send (pr-str message)))
-
-    ; Send
-    (if (< (rand) p-loss)
-      net ; whoops, lost ur packet
-      (let [src  (:src message)
-            dest (:dest message)
-            q    (queue-for net dest)]
-        (.put q {:deadline deadline
-                 :message  message})
-        net))))
+                    (validate-msg n))]
+    (r/with-rng (rng-for net (:src message))
+      (let [env (envelope n message nil)]
+
+        ; Journal
+        (j/log-send! journal message)
+
+        ; Log
+        (when log-send? (info :send (pr-str message)))
+
+        ; Send
+        (cond
+          ; There's nobody there anymore.
+          (not (get (:queues n) (:dest message)))
+          (do (j/log-drop! journal message #{:departed})
+              (node-not-found! (:dest message)))
+
+          ; This node has crashed. Its messages go nowhere.
+          (contains? (:crashed n) (:src message))
+          (do (j/log-drop! journal message #{:crashed})
+              net)
+
+          ; Or it's about to crash, just before sending this.
+          (crash! net :send (:src message) message)
+          (do (j/log-drop! journal message #{:crashed})
+              net)
+
+          (< (r/rand) p-loss)
+          net ; whoops, lost ur packet
+
+          true
+          (do (doseq [env (apply-rules n env)]
+                (enqueue! net env))
+              ; Maybe deliver it again
+              (when (and (pos? p-dup)
+                         (not (u/involves-client? message))
+                         (< (r/rand) p-dup))
+                (doseq [env (apply-rules n (envelope n message #{:duplicate}))]
+                  (enqueue! net env)))
+              net))))))
+
+(defn drop-tail!
+  "Approximates a bounded inbox. Called just after a node takes a message from
+  its queue. Every other message which is already due has, from the node's
+  point of view, been waiting in its inbox alongside the message just taken.
+  We keep the earliest of those, up to the inbox's capacity, and drop the
+  rest: they arrived when the inbox was already full.
+
+  This is only an approximation. We trim when the node takes a message, not
+  when messages arrive, so a node which isn't reading can have more than
+  capacity messages due at once; they're dropped when it reads again. And we
+  judge which messages arrived late by their deadlines, not by when the
+  inbox actually filled."
+  [net ^PriorityBlockingQueue q capacity]
+  (let [{:keys [journal] :as n} @net
+        now (now-nanos n)
+        ; Only this node's receiver takes from q, so everything we poll here
+        ; is at least as due as what we peeked.
+        due (loop [due []]
+              (let [e (.peek q)]
+                (if (and e (<= (:deadline e) now))
+                  (recur (conj due (.poll q)))
+                  due)))
+        [kept dropped] (split-at (dec capacity) due)]
+    (doseq [e kept]
+      (.put q e))
+    (doseq [e dropped]
+      (j/log-drop! journal (:message e) #{:inbox-full}))))
 
 (defn recv!
   "Receive a message for the given node. Returns the message, and mutates the
@@ -226,12 +979,26 @@
   ; Fetch a message
   (when-let [envelope (.poll (queue-for net node)
                              timeout-ms TimeUnit/MILLISECONDS)]
-    (let [{:keys [deadline message]} envelope
-          dt (/ (- deadline (System/nanoTime)) 1e6)
-          {:keys [log-recv? partitions journal]} @net]
-
-      (when-not (some #{(:src message)} (get partitions node))
-        ; No partition, OK, let's go!
+    (let [{:keys [deadline message tags]} envelope
+          {:keys [log-recv? partitions journal inbox-capacity processes]
+           :as n} @net
+          dt (/ (- deadline (now-nanos n)) 1e6)]
+
+      (when (and inbox-capacity (contains? processes node) (<= dt 0))
+        (drop-tail! net (queue-for net node) inbox-capacity))
+
+      (cond
+        ; Partitioned
+        (some #{(:src message)} (get partitions node))
+        nil
+
+        ; This node has crashed; it never sees the message.
+        (contains? (:crashed n) node)
+        (do (j/log-drop! journal message #{:crashed})
+            nil)
+
+        ; OK, let's go!
+        true
         (do (when (pos? dt)
               ; This message isn't due for a bit; block until it's ready
               (Thread/sleep dt))
@@ -240,7 +1007,11 @@
             (when log-recv? (info :recv (pr-str message)))
 
             ; Journal
-            (j/log-recv! journal message)
+            (j/log-recv! journal message tags)
+
+            ; If this message is a crash point, the node crashes right after
+            ; reading it: it'll never get a word out.
+            (crash! net :recv node message)
 
             ; And deliver!
             message)))))
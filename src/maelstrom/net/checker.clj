@@ -31,14 +31,94 @@
   (->> journal
        (t/fuse {:send-count (t/count j/sends)
                 :recv-count (t/count j/recvs)
+                :drop-count (t/count j/drops)
                 :msg-count  (->> (t/map (comp :id :message))
                                  ; (fast-cardinality))})))
                                  (j/dense-int-cardinality))})))
 
-(def stats
-  (t/fuse {:all     (->> (t/map identity) basic-stats)
-           :clients (->> j/clients          basic-stats)
-           :servers (->> j/servers          basic-stats)}))
+(defn region-stats
+  "A fold which breaks down traffic between servers by [src-region
+  dest-region], given a map of nodes to regions. Nodes without a region (e.g.
+  services) have region nil."
+  [regions]
+  (->> j/servers
+       (t/group-by (fn region-pair [event]
+                     (let [m (:message event)]
+                       [(get regions (:src m)) (get regions (:dest m))])))
+       basic-stats))
+
+(defn distances
+  "Takes a topology map of nodes to neighbors, and a starting node. Returns a
+  map of every node reachable from it to its distance, in hops."
+  [topo node]
+  (loop [dists    {node 0}
+         frontier [node]]
+    (if-not (seq frontier)
+      dists
+      (let [[dists frontier']
+            (reduce (fn [[dists frontier'] node]
+                      (reduce (fn [[dists frontier' :as acc] neighbor]
+                                (if (contains? dists neighbor)
+                                  acc
+                                  [(assoc dists neighbor
+                                          (inc (get dists node)))
+                                   (conj frontier' neighbor)]))
+                              [dists frontier']
+                              (get topo node)))
+                    [dists []]
+                    frontier)]
+        (recur dists frontier')))))
+
+(defn diameter
+  "The longest shortest path between any two nodes in a topology, in hops, or
+  nil if some nodes can't reach each other at all."
+  [topo]
+  (let [nodes (set (concat (keys topo) (apply concat (vals topo))))]
+    (reduce (fn [diameter node]
+              (let [dists (distances topo node)]
+                (if (= (count nodes) (count dists))
+                  (max diameter (reduce max 0 (vals dists)))
+                  (reduced nil))))
+            0
+            nodes)))
+
+(def topologies
+  "A fold which finds every distinct topology clients sent to nodes (e.g. in
+  the broadcast workload), in the order they were first sent."
+  (->> j/sends
+       (t/filter (fn topology? [e]
+                   (let [m (:message e)]
+                     (and (u/client? (:src m))
+                          (= "topology" (:type (:body m)))))))
+       (t/map (fn [e] [(:id e) (:topology (:body (:message e)))]))
+       (t/into [])
+       (t/post-combine (fn [topos]
+                         (->> topos
+                              (sort-by first)
+                              (map second)
+                              distinct)))))
+
+(defn topology-stats
+  "Summarizes a topology: how many links it has, the most neighbors any node
+  has, and its diameter. Low-diameter topologies should deliver messages in
+  fewer hops, and high fan-out ones may send more messages per op."
+  [topo]
+  {:links      (->> topo
+                    (mapcat (fn [[a bs]] (map (fn [b] (set [a b])) bs)))
+                    distinct
+                    count)
+   :max-degree (reduce max 0 (map (comp count val) topo))
+   :diameter   (diameter topo)})
+
+(defn stats
+  "A fold for all the statistics we compute over a test's journal."
+  [test]
+  (let [regions (:regions test)]
+    (t/fuse (cond-> {:all        (->> j/messages         basic-stats)
+                     :clients    (->> j/clients          basic-stats)
+                     :servers    (->> j/servers          basic-stats)
+                     :topologies topologies}
+              (seq regions) (assoc :regions (region-stats regions))))))
 
 (defn checker
   "A Jepsen checker which extracts the journal and analyzes its statistics."
@@ -48,7 +128,7 @@
       (let [; Fire off the plotter immediately; it can run without us
             plot (future (viz/plot-analemma! test))
             ; Compute stats
-            stats   (->> stats
+            stats   (->> (stats test)
                          (j/tesser-journal test))
             ; Add msgs-per-op stats, so we can tell roughly how many messages
             ; exchanged per logical operation
@@ -64,7 +144,14 @@
                                             op-count)))
                         (assoc-in [:servers :msgs-per-op]
                                   (float (/ (:msg-count (:servers stats))
-                                            op-count)))))]
+                                            op-count)))))
+            ; Report the shape of each topology nodes were given, next to
+            ; msgs-per-op, so you can weigh latency against fan-out
+            topos (map topology-stats (:topologies stats))
+            stats (if (seq topos)
+                    (assoc-in stats [:servers :topologies] (vec topos))
+                    stats)
+            stats (dissoc stats :topologies)]
         ; Block on plot
         @plot
         (assoc stats :valid? true)))))
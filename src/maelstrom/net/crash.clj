@@ -0,0 +1,57 @@
+(ns maelstrom.net.crash
+  "Crash points kill a node at a specific moment: right after it receives, or
+  right before it sends, a particular message. Random kills rarely land
+  between, say, a leader's log append and its reply; crash points let you aim.
+
+  Crash points are given as an EDN file containing a vector of maps like:
+
+    {:name    :ack-crash        ; Optional; defaults to :crash-0, :crash-1, ...
+     :node    \"n2\"              ; A node ID, or a collection of them
+     :on      :send             ; :send or :recv
+     :type    \"append_entries_ok\" ; A body :type, or a collection of them
+     :where   {:term 3}         ; Optional; other body fields to match
+     :nth     3                 ; Crash on the nth matching message; default 1
+     :restart 1000}             ; Optional; restart the node after this many ms
+
+  Every criterion but :on is optional. Each crash point fires at most once.
+  When it fires, the node is cut off from the network immediately: a message
+  it was about to send is dropped, and so is everything it sends after
+  receiving a crashing message. The node's process is then killed, and its
+  unsynced writes lost, just like the kill nemesis. Without :restart, the node
+  stays down until something else (e.g. the kill nemesis) restarts it. Restarts
+  still pending when the test tears down never happen."
+  (:require [clojure.edn :as edn]
+            [maelstrom.net.rules :as rules]
+            [schema.core :as s]))
+
+(def CrashPoint
+  "The schema for a crash point."
+  {(s/optional-key :name)    s/Keyword
+   (s/optional-key :node)    rules/Matcher
+   :on                       (s/enum :send :recv)
+   (s/optional-key :type)    rules/Matcher
+   (s/optional-key :where)   {s/Keyword s/Any}
+   (s/optional-key :nth)     (s/constrained s/Int pos?)
+   (s/optional-key :restart) s/Num})
+
+(defn load-crash-points
+  "Reads a vector of crash points from an EDN file, validates them, and
+  assigns each a :name if it doesn't have one. Throws if they're malformed."
+  [file]
+  (let [points (edn/read-string (slurp file))]
+    (s/validate [CrashPoint] points)
+    (->> points
+         (map-indexed (fn [i point]
+                        (update point :name
+                                #(or % (keyword (str "crash-" i))))))
+         vec)))
+
+(defn match?
+  "Does a crash point apply to the given node sending (:send) or receiving
+  (:recv) a message?"
+  [{:keys [node on type where]} event node-id message]
+  (let [body (:body message)]
+    (and (= on event)
+         (rules/match-value? node node-id)
+         (rules/match-value? type (:type body))
+         (every? (fn [[k v]] (= v (get body k))) where))))
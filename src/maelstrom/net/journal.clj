@@ -6,9 +6,15 @@
 
   A journal is logically a sequence of events, each of which is a map like
 
-  {:type      :send, :recv
+  {:type      :send, :recv, :drop, or :rule (when a rule altered a message)
    :time      An arbitrary linear timestamp in nanoseconds
-   :message   The message exchanged}
+   :message   The message exchanged
+   :tags      Either nil, or a set of keywords noting something unusual about
+              this event; e.g. #{:duplicate} for a duplicated delivery.}
+
+  The journal also records when nodes are paused and resumed, so we can show
+  those intervals alongside messages. These events have :type :pause or
+  :resume, and their :message is just {:node node-id}.
 
   Because Maelstrom tests may generate a LOT of messages, these events are
   journaled to disk incrementally, rather than stored entirely in-memory.
@@ -50,7 +56,7 @@
                                Set)))
 
 ; We're going to doing a LOT of event manipulation, so speed matters.
-(defrecord Event [^long id ^long time type message])
+(defrecord Event [^long id ^long time type message tags])
 
 (defn write-body!
   "We burn a huge amount of time in interning keywords during body
@@ -72,11 +78,12 @@
   (-> {maelstrom.net.journal.Event
        {"ev" (reify WriteHandler
                (write [_ w e]
-                 (.writeTag     w "ev" 4)
+                 (.writeTag     w "ev" 5)
                  (.writeInt     w (:id e))
                  (.writeInt     w (:time e))
                  (.writeObject  w (:type e) true)
-                 (.writeObject  w (:message e))))}
+                 (.writeObject  w (:message e))
+                 (.writeObject  w (:tags e) true)))}
 
        maelstrom.net.message.Message
        {"msg" (reify WriteHandler
@@ -95,11 +102,14 @@
   "How should Fressian read different tags?"
   (-> {"ev" (reify ReadHandler
               (read [_ r tag component-count]
-                (assert (= 4 component-count))
+                ; Older journals don't have tags
+                (assert (<= 4 component-count 5))
                 (Event. (.readInt r)
                         (.readInt r)
                         (.readObject r)
-                        (.readObject r))))
+                        (.readObject r)
+                        (when (= 5 component-count)
+                          (.readObject r)))))
 
        "msg" (reify ReadHandler
                (read [_ r tag component-count]
@@ -223,20 +233,65 @@
   (fress/write-object (local-writer journal) event))
 
 (defn log-send!
-  "Logs a send operation"
-  [journal message]
-  (log-event! journal (Event. (swap! (:next-id journal) inc)
-                              (linear-time-nanos)
-                              :send
-                              message)))
+  "Logs a send operation, optionally with a set of tags."
+  ([journal message]
+   (log-send! journal message nil))
+  ([journal message tags]
+   (log-event! journal (Event. (swap! (:next-id journal) inc)
+                               (linear-time-nanos)
+                               :send
+                               message
+                               tags))))
 
 (defn log-recv!
-  "Logs a receive operation"
-  [journal message]
+  "Logs a receive operation, optionally with a set of tags."
+  ([journal message]
+   (log-recv! journal message nil))
+  ([journal message tags]
+   (log-event! journal (Event. (swap! (:next-id journal) inc)
+                               (linear-time-nanos)
+                               :recv
+                               message
+                               tags))))
+
+(defn log-drop!
+  "Logs that the network dropped a message before its recipient could receive
+  it, with a set of tags explaining why; e.g. #{:inbox-full}."
+  [journal message tags]
+  (log-event! journal (Event. (swap! (:next-id journal) inc)
+                              (linear-time-nanos)
+                              :drop
+                              message
+                              tags)))
+
+(defn log-rule!
+  "Logs that one of the network's rules applied to a message, with a set of
+  tags naming the rule and its action; see maelstrom.net.rules/tags."
+  [journal message tags]
   (log-event! journal (Event. (swap! (:next-id journal) inc)
                               (linear-time-nanos)
-                              :recv
-                              message)))
+                              :rule
+                              message
+                              tags)))
+
+(defn log-node-event!
+  "Logs that something happened to a node itself, rather than a message:
+  type is :pause or :resume."
+  [journal type node]
+  (log-event! journal (Event. (swap! (:next-id journal) inc)
+                              (linear-time-nanos)
+                              type
+                              {:node node}
+                              nil)))
+
+(def message-types
+  "The types of events which concern a message."
+  #{:send :recv :drop :rule})
+
+(defn message-event?
+  "Is this event about a message, as opposed to a node?"
+  [^Event event]
+  (contains? message-types (.type event)))
 
 (defn involves-client?
   "Takes an event and returns true iff it was sent to or received from a
@@ -248,10 +303,11 @@
   "A fold which strips out initialization messages."
   [& [f]]
   (t/remove (fn [^Event event]
-              (let [t (:type (.body ^maelstrom.net.message.Message
-                                    (.message event)))]
-                (or (= t "init")
-                    (= t "init_ok"))))
+              (and (message-event? event)
+                   (let [t (:type (.body ^maelstrom.net.message.Message
+                                         (.message event)))]
+                     (or (= t "init")
+                         (= t "init_ok")))))
             f))
 
 ;; Analysis
@@ -310,13 +366,23 @@
   "Fold which filters a journal to just receives."
   (t/filter (fn recv? [^Event e] (identical? :recv (.type e)))))
 
+(def drops
+  "Fold which filters a journal to just drops."
+  (t/filter (fn drop? [^Event e] (identical? :drop (.type e)))))
+
+(def messages
+  "Fold which filters a journal to just events about messages."
+  (t/filter message-event?))
+
 (def clients
   "Fold which filters a journal to just messages to/from clients"
-  (t/filter involves-client?))
+  (t/filter (fn client? [e]
+              (and (message-event? e) (involves-client? e)))))
 
 (def servers
   "Fold which filters a journal to just messages between servers."
-  (t/remove involves-client?))
+  (t/filter (fn server? [e]
+              (and (message-event? e) (not (involves-client? e))))))
 
 (t/deftransform up-to-event
   "A fold which selects all contiguous events, in event order, up to but not
@@ -0,0 +1,89 @@
+(ns maelstrom.net.rules
+  "Scriptable rules for tampering with specific messages, so you can reproduce
+  a particular bug: \"drop every append_entries from n1 to n3 after 10
+  seconds\", or \"delay all vote replies by 500 ms\".
+
+  Rules are given as an EDN file containing a vector of maps like:
+
+    {:name   :slow-votes        ; Optional; defaults to :rule-0, :rule-1, ...
+     :src    \"n1\"               ; A node ID, or a collection of them
+     :dest   [\"n3\" \"n4\"]
+     :type   \"vote_ok\"          ; A body :type, or a collection of them
+     :after  10                 ; Seconds since the test began
+     :before 20
+     :action :delay             ; :drop, :delay, :duplicate, or :hold
+     :delay  500}               ; For :delay, how many ms to add
+
+  Every criterion (:src, :dest, :type, :after, :before) is optional, and an
+  omitted criterion matches anything, including messages to and from clients.
+  Every rule which matches a message applies to it, in order.
+
+  :hold keeps matching messages in the network until the rule's window
+  closes, then delivers them, so it needs a :before. Delays and holds apply
+  after --net-ordering fifo does its work, so they can reorder a link."
+  (:require [clojure.edn :as edn]
+            [schema.core :as s]))
+
+(def Matcher
+  "A rule can match a single value, or any of a collection of them."
+  (s/cond-pre s/Str [s/Str] #{s/Str}))
+
+(def Rule
+  "The schema for a rule."
+  {(s/optional-key :name)   s/Keyword
+   (s/optional-key :src)    Matcher
+   (s/optional-key :dest)   Matcher
+   (s/optional-key :type)   Matcher
+   (s/optional-key :after)  s/Num
+   (s/optional-key :before) s/Num
+   :action                  (s/enum :drop :delay :duplicate :hold)
+   (s/optional-key :delay)  s/Num})
+
+(defn check-rule
+  "Validates a rule, beyond what its schema can say. Returns an error string,
+  or nil."
+  [{:keys [action delay before]}]
+  (cond (and (= :delay action) (nil? delay))
+        ":delay rules need a :delay, in ms"
+
+        (and (= :hold action) (nil? before))
+        ":hold rules need a :before, so we know when to release messages"))
+
+(defn load-rules
+  "Reads a vector of rules from an EDN file, validates them, and assigns each
+  a :name if it doesn't have one. Throws if the rules are malformed."
+  [file]
+  (let [rules (edn/read-string (slurp file))]
+    (s/validate [Rule] rules)
+    (->> rules
+         (map-indexed (fn [i rule]
+                        (when-let [err (check-rule rule)]
+                          (throw (IllegalArgumentException.
+                                   (str "Rule " i " is malformed: " err))))
+                        (update rule :name
+                                #(or % (keyword (str "rule-" i))))))
+         vec)))
+
+(defn match-value?
+  "Does a single criterion (a value, a collection of values, or nil) match a
+  value?"
+  [criterion v]
+  (cond (nil? criterion)    true
+        (coll? criterion)   (boolean (some #{v} criterion))
+        :else               (= criterion v)))
+
+(defn match?
+  "Does a rule apply to a message sent t seconds into the test?"
+  [{:keys [src dest type after before]} t message]
+  (and (match-value? src  (:src message))
+       (match-value? dest (:dest message))
+       (match-value? type (:type (:body message)))
+       (or (nil? after)  (<= after t))
+       (or (nil? before) (< t before))))
+
+(defn tags
+  "The tags we journal when a rule applies to a message: :rule, the rule's
+  action, and its name, namespaced so it can't be mistaken for an action or
+  any other tag. For example, #{:rule :delay :rule/slow-votes}."
+  [rule]
+  #{:rule (:action rule) (keyword "rule" (name (:name rule)))})
@@ -0,0 +1,83 @@
+(ns maelstrom.net.tamper
+  "Functions for altering message bodies in transit: corrupting them at
+  random, or rewriting a Byzantine node's messages so that different
+  recipients see conflicting content.
+
+  We never touch a body's :type, :msg_id, or :in_reply_to. Mangling those
+  makes a message unroutable, which looks to the node much like a lost
+  message, and we already have faults for that."
+  (:require [maelstrom [random :as r]]))
+
+(def protected-keys
+  "Body keys we never tamper with."
+  #{:type :msg_id :in_reply_to})
+
+(defn tamperable-keys
+  "The keys of a body we're allowed to tamper with, in a stable order."
+  [body]
+  (->> (keys body)
+       (remove protected-keys)
+       (sort-by str)))
+
+(defn flip
+  "Changes a single value into a different value of (roughly) the same kind.
+  The result is never equal to v."
+  [v]
+  (cond (boolean? v)    (not v)
+        (number? v)     (inc v)
+        (string? v)     (str "~" v)
+        (map? v)        (if (seq v)
+                          (dissoc v (first (sort-by str (keys v))))
+                          {"~" nil})
+        (sequential? v) (if (seq v)
+                          (vec (butlast v))
+                          [nil])
+        (nil? v)        0
+        :else           nil))
+
+(defn corrupt
+  "Corrupts a message body at random: flips the value of one field, drops a
+  field, or swaps the values of two fields which differ. Returns [body' kind],
+  where kind is :flip, :drop-key, or :swap; or nil if there was nothing to
+  corrupt. Body' always differs from body."
+  [body]
+  (let [ks    (vec (tamperable-keys body))
+        pairs (vec (for [a ks, b ks
+                         :when (and (neg? (compare (str a) (str b)))
+                                    (not= (get body a) (get body b)))]
+                     [a b]))]
+    (when (seq ks)
+      (let [kind (if (seq pairs)
+                   (r/rand-nth [:flip :drop-key :swap])
+                   (r/rand-nth [:flip :drop-key]))]
+        (case kind
+          :flip     (let [k (r/rand-nth ks)]
+                      [(update body k flip) kind])
+          :drop-key [(dissoc body (r/rand-nth ks)) kind]
+          :swap     (let [[a b] (r/rand-nth pairs)]
+                      [(assoc body a (get body b), b (get body a)) kind]))))))
+
+(defn perturb
+  "Alters a value by an amount determined by the integer n, so that different
+  ns yield conflicting values. Recurs into collections."
+  [n v]
+  (cond (boolean? v)    (if (odd? n) (not v) v)
+        (number? v)     (+ v n)
+        (string? v)     (str v "~" n)
+        (map? v)        (->> v
+                             (map (fn [[k v]] [k (perturb n v)]))
+                             (into {}))
+        (sequential? v) (mapv (partial perturb n) v)
+        :else           v))
+
+(defn equivocate
+  "Rewrites a body sent to the recipient with the given index (0, 1, ...).
+  Every recipient gets its own consistent version of the truth: values sent
+  to recipient i are perturbed by i + 1, so no two recipients see the same
+  numbers or strings. Returns body unchanged if there is nothing to rewrite."
+  [dest-index body]
+  (let [n (inc dest-index)]
+    (reduce (fn [body k]
+              (update body k (partial perturb n)))
+            body
+            (tamperable-keys body))))
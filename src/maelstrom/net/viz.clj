@@ -19,8 +19,11 @@
   "Takes a journal and returns the collection of all nodes involved in it."
   [journal]
   (->> journal
-       (map :message)
-       (mapcat (juxt :src :dest))
+       (mapcat (fn [event]
+                 (let [m (:message event)]
+                   (if (j/message-event? event)
+                     [(:src m) (:dest m)]
+                     [(:node m)]))))
        distinct
        u/sort-clients))
 
@@ -29,7 +32,8 @@
 
   {:from      A dot node
    :to        A dot node
-   :message   The message exchanged}"
+   :message   The message exchanged
+   :tags      Tags from the receive event, e.g. #{:duplicate}}"
   ([journal]
    (messages {} 1 (seq journal)))
   ; froms is a map of message IDs to the dot node of their origin.
@@ -46,13 +50,21 @@
                                             :step step})
                            (inc step)
                            (next journal))
+           ; The network dropped this message; it never arrives.
+           :drop (messages froms (inc step) (next journal))
+           ; A rule altered a message; we'll see it when it's received.
+           :rule (messages froms (inc step) (next journal))
+           ; Nodes paused and resumed; see pauses.
+           (:pause :resume) (messages froms (inc step) (next journal))
+
            ; We're receiving a message; emit an edge.
            :recv (let [from (get froms id)]
                    (assert from)
                    (cons {:from     from
                           :to       {:node (:dest message)
                                      :step step}
-                          :message  message}
+                          :message  message
+                          :tags     (:tags event)}
                          (messages froms (inc step) (next journal))))))))))
 
 ;; SVG Rendering
@@ -107,12 +119,45 @@
   [layout step]
   (float (* (:y-step layout) (+ 1.5 step))))
 
+(defn pauses
+  "Takes a journal and constructs a sequence of intervals when nodes were
+  paused: each a map of {:node, :start step, :end step}. Pauses which were
+  never resumed run to the end of the journal."
+  [journal]
+  (loop [journal  (seq journal)
+         step     1
+         paused   {} ; node -> start step
+         pauses   []]
+    (if-not journal
+      (into pauses (map (fn [[node start]]
+                          {:node node, :start start, :end step})
+                        paused))
+      (let [event (first journal)
+            node  (:node (:message event))]
+        (case (:type event)
+          :pause  (recur (next journal) (inc step)
+                         (assoc paused node step)
+                         pauses)
+          :resume (recur (next journal) (inc step)
+                         (dissoc paused node)
+                         (if-let [start (get paused node)]
+                           (conj pauses {:node node, :start start, :end step})
+                           pauses))
+          (recur (next journal) (inc step) paused pauses))))))
+
 (defn message->color
   "Takes a message event and returns what color to use in drawing it."
-  [{:keys [from to message]}]
+  [{:keys [from to message tags]}]
   (cond (= "error" (:type (:body message)))
         "#FF1E90"
 
+        (or (contains? tags :corrupted)
+            (contains? tags :equivocated))
+        "#E8A317"
+
+        (contains? tags :duplicate)
+        "#A05CE5"
+
         (u/involves-client? message)
         "#81BFFC"
 
@@ -192,7 +237,9 @@
              ; Don't overshoot the arrowhead
              :width  (- length 4)
              :fill   (message->color message)}
-      [:title (str (:src m) " → " (:dest m)
+      [:title (str (when (seq (:tags message))
+                     (str (pr-str (:tags message)) " "))
+                   (:src m) " → " (:dest m)
                    " " (pr-str (:body m)))]]
      ; Arrowhead
      [:use {"xlink:href" "#ahead", :x length, :y 0}]
@@ -229,6 +276,23 @@
                        :y2 (y layout (inc (:step-count layout)))}])
              (:nodes layout))))
 
+(defn pause-bars
+  "Takes a layout and renders a translucent bar over each node's line while
+  it was paused."
+  [layout]
+  (->> layout
+       :journal
+       pauses
+       (map (fn [{:keys [node start end]}]
+              [:rect {:x      (- (x layout node) 6)
+                      :y      (y layout start)
+                      :width  12
+                      :height (- (y layout end) (y layout start))
+                      :fill   "#E9A0A0"
+                      :fill-opacity 0.6}
+               [:title (str node " paused")]]))
+       (cons :g)))
+
 (defn message-lines
   "Takes a layout and a journal, and produces a set of lines for each message."
   [layout]
@@ -318,6 +382,7 @@ polygon { fill: #000; }"
                (glow-filter)]
               (node-labels      layout)
               (node-lines       layout)
+              (pause-bars       layout)
               (message-lines    layout)
               (truncated-notice layout)
               )
@@ -1,23 +1,51 @@
 (ns maelstrom.process
-  "Handles process spawning and IO"
+  "Handles process spawning and IO.
+
+  Nodes normally exchange messages with us as JSON lines on stdin and stdout.
+  With the :tcp or :unix transports, we instead listen on a local socket for
+  each node, and tell the node where to connect via the MAELSTROM_TRANSPORT and
+  MAELSTROM_ADDRESS environment variables. Messages on the socket are framed
+  just as they would be on stdin and stdout, and the node's stdout is free for
+  debugging output, which we log alongside stderr.
+
+  Messages are JSON lines by default. With the :cbor or :msgpack codecs, they
+  are length-prefixed binary frames instead (see maelstrom.codec), and we tell
+  the node which codec to use via the MAELSTROM_CODEC environment variable."
   (:require [amalloy.ring-buffer :as ring-buffer]
             [clojure [pprint :refer [pprint]]
                      [string :as str]]
             [clojure.java.io :as io]
+            [clojure.java.shell :refer [sh]]
             [clojure.tools.logging :refer [info warn]]
             [byte-streams :as bs]
             [cheshire.core :as json]
             [jepsen.util :refer [with-thread-name]]
-            [maelstrom [net :as net]]
+            [maelstrom [codec :as codec]
+                       [net :as net]]
             [slingshot.slingshot :refer [try+ throw+]])
   (:import (java.lang Process
+                      ProcessHandle
                       ProcessBuilder
                       ProcessBuilder$Redirect)
-           (java.io File
+           (java.io BufferedInputStream
+                    BufferedOutputStream
+                    DataInputStream
+                    DataOutputStream
+                    EOFException
+                    File
+                    InputStream
                     IOException
+                    OutputStream
                     OutputStreamWriter
                     Writer)
-           (java.util.concurrent TimeUnit)))
+           (java.net InetAddress
+                     InetSocketAddress
+                     StandardProtocolFamily)
+           (java.nio ByteBuffer)
+           (java.nio.channels ServerSocketChannel
+                              SocketChannel)
+           (java.util.concurrent TimeUnit)
+           (clojure.lang Reflector)))
 
 (def debug-buffer-size
   "Number of lines of stderr and stdout we store for debugging assistance"
@@ -32,8 +60,9 @@
             (transient {})
             m)))
 
-(defn parse-msg
-  "Parses a line as a message, throwing appropriate exceptions.
+(defn coerce-msg
+  "Takes a freshly decoded message with string keys, converts its keys to
+  keywords, and validates it, throwing if it's malformed.
 
   We may be dealing in arbitrary JSON payloads, and coercing all keys to
   keywords may really mess up maps like {\"9\": true}. I don't know a rigorous
@@ -41,14 +70,8 @@
   but I don't really underSTAND it yet. For right now, we convert just the keys
   in the message and body, to one level. Nothing is nested deeper than that
   anyway."
-  [node-id line]
-  (let [parsed (try (json/parse-string line)
-                    (catch com.fasterxml.jackson.core.JsonParseException e
-                      (throw+ {:type :line-not-valid-json
-                               :line line}
-                              (str "Node " node-id
-                                   " printed a line to STDOUT which was not well-formed JSON:\n" line "\nDid you mean to encode this line as JSON? Or was this line intended for STDERR? See doc/protocol.md for more guidance."))))
-        ; Convert string keys to keywords
+  [node-id parsed]
+  (let [; Convert string keys to keywords
         message (-> parsed
                     keywordize-keys-1
                     (update :body keywordize-keys-1))]
@@ -65,13 +88,39 @@
                    "\nSee doc/protocol.md for more guidance.")))
     message))
 
+(defn parse-msg
+  "Parses a JSON line as a message, throwing appropriate exceptions."
+  [node-id line]
+  (let [parsed (try (json/parse-string line)
+                    (catch com.fasterxml.jackson.core.JsonParseException e
+                      (throw+ {:type :line-not-valid-json
+                               :line line}
+                              (str "Node " node-id
+                                   " printed a line to STDOUT which was not well-formed JSON:\n" line "\nDid you mean to encode this line as JSON? Or was this line intended for STDERR? See doc/protocol.md for more guidance."))))]
+    (coerce-msg node-id parsed)))
+
+(defn parse-frame
+  "Decodes a binary frame as a message, using the given codec, throwing
+  appropriate exceptions."
+  [node-id codec frame]
+  (let [parsed (try (codec/decode codec frame)
+                    (catch Exception e
+                      (throw+ {:type  :frame-not-valid
+                               :codec codec}
+                              e
+                              (str "Node " node-id
+                                   " sent a frame which was not valid "
+                                   (name codec) ". See doc/protocol.md for more guidance."))))]
+    (coerce-msg node-id parsed)))
+
 (defmacro io-thread
   "Spawns an IO thread for a process. Takes a running? atom, a node id, a
   thread name (e.g. \"stdin\"), [sym closable-expression ...] bindings (for
   with-open), a single loop-recur binding, and a body. Spawns a future, holding
   the closeable open, evaluating body in the loop-recur bindings as long as
   `running?` is true, and catching/logging exceptions. Body should return the
-  next value for the loop iteration, or `nil` to terminate."
+  next value for the loop iteration, or `nil` to terminate. If body throws, we
+  log the exception and carry on, with `nil` as the next value."
   [running? node-id thread-type open-bindings loop-binding & body]
   `(future
      (with-thread-name (str ~node-id " " ~thread-type)
@@ -86,24 +135,29 @@
              (if-not (deref ~running?)
                ; We're done
                :done
-               (recur (try ~@body
-                           (catch IOException e#
-                             ; If the process crashes, we're going to hit
-                             ; IOExceptions trying to write/read streams.
-                             ; That's fine--we're going to learn about crashes
-                             ; when the process shutdown code checks the exit
-                             ; status.
-                             )
-                           (catch InterruptedException e#
-                             ; We might be interrupted if setup fails, but it's
-                             ; not our job to exit here--we need to keep the
-                             ; process's streams up and running so we can tell
-                             ; if it terminated normally. We'll be terminated
-                             ; by the DB teardown process.
-                             )
-                           (catch Throwable t#
-                             (warn t# "Error!")
-                             nil))))))
+               (let [next# (try (let [v# (do ~@body)]
+                                  (if (nil? v#) ::done v#))
+                                (catch IOException e#
+                                  ; If the process crashes, we're going to
+                                  ; hit IOExceptions trying to write/read
+                                  ; streams. That's fine--we're going to
+                                  ; learn about crashes when the process
+                                  ; shutdown code checks the exit status.
+                                  )
+                                (catch InterruptedException e#
+                                  ; We might be interrupted if setup fails,
+                                  ; but it's not our job to exit here--we
+                                  ; need to keep the process's streams up
+                                  ; and running so we can tell if it
+                                  ; terminated normally. We'll be terminated
+                                  ; by the DB teardown process.
+                                  )
+                                (catch Throwable t#
+                                  (warn t# "Error!")
+                                  nil))]
+                 (if (= ::done next#)
+                   :done
+                   (recur next#))))))
          (catch IOException e#
            ; with-open is going to try to close things like OutputWriters,
            ; which will actually throw if the process has crashed, because they
@@ -112,12 +166,118 @@
            :crashed
            )))))
 
+(def transports
+  "The ways nodes can exchange messages with us."
+  #{:stdio :tcp :unix})
+
+(defn ^ServerSocketChannel unix-server-channel
+  "Opens a server channel bound to a Unix socket at the given path. Unix
+  sockets need JDK 16 or higher; we call these reflectively so that Maelstrom
+  still loads on older JDKs."
+  [^String path]
+  (let [family (Enum/valueOf StandardProtocolFamily "UNIX")
+        ^java.net.SocketAddress addr
+               (Reflector/invokeStaticMethod
+                 "java.net.UnixDomainSocketAddress" "of" (object-array [path]))
+        ch     (Reflector/invokeStaticMethod
+                 ServerSocketChannel "open" (object-array [family]))]
+    (doto ^ServerSocketChannel ch
+      (.bind addr))))
+
+(defn listen!
+  "Opens a socket for a node to connect to, for the :tcp and :unix
+  transports. Returns a map of:
+
+    :channel    The ServerSocketChannel
+    :address    The address to give the node: host:port for :tcp, and a path
+                for :unix
+    :file       For :unix, the socket's File, which we delete on shutdown
+    :conn       A future of the node's SocketChannel, once it connects. If
+                the listener is closed first, yields the IOException
+                instead."
+  [transport node-id]
+  (let [[ch addr file]
+        (case transport
+          :tcp  (let [ch (doto (ServerSocketChannel/open)
+                           (.bind (InetSocketAddress.
+                                    (InetAddress/getLoopbackAddress) 0)))
+                      a  ^InetSocketAddress (.getLocalAddress ch)]
+                  [ch (str (.getHostString a) ":" (.getPort a)) nil])
+          :unix (let [file (io/file (System/getProperty "java.io.tmpdir")
+                                    (str "maelstrom-" node-id "-"
+                                         (System/nanoTime) ".sock"))
+                      path (.getCanonicalPath file)]
+                  [(unix-server-channel path) path file]))]
+    {:channel ch
+     :address addr
+     :file    file
+     :conn    (future
+                (try (.accept ^ServerSocketChannel ch)
+                     (catch IOException e
+                       e)))}))
+
+(defn ^SocketChannel await-conn
+  "Waits for a node to connect to its listener, and returns the connection.
+  Throws an IOException if the listener was closed first."
+  [listener]
+  (let [c @(:conn listener)]
+    (if (instance? IOException c)
+      (throw c)
+      c)))
+
+(defn close-listener!
+  "Closes a node's listener, its connection, if any, and its socket file."
+  [{:keys [^ServerSocketChannel channel conn ^File file]}]
+  (.close channel)
+  (let [c @conn]
+    (when (instance? SocketChannel c)
+      (.close ^SocketChannel c)))
+  (when file
+    (.delete file)))
+
+; Channels/newInputStream and newOutputStream both hold the channel's
+; blockingLock while they wait, so on JDK 17 and earlier a node's writer
+; can't make progress while its reader is blocked (JDK-8279339). We read and
+; write the channel directly instead.
+
+(defn ^InputStream channel-input-stream
+  "An InputStream which reads from a blocking SocketChannel."
+  [^SocketChannel ch]
+  (proxy [InputStream] []
+    (read
+      ([]
+       (let [buf (ByteBuffer/allocate 1)]
+         (if (neg? (.read ch buf))
+           -1
+           (bit-and 0xff (.get buf 0)))))
+      ([^bytes bs]
+       (.read ^InputStream this bs 0 (alength bs)))
+      ([^bytes bs off len]
+       (if (zero? len)
+         0
+         (.read ch (ByteBuffer/wrap bs off len)))))))
+
+(defn ^OutputStream channel-output-stream
+  "An OutputStream which writes to a blocking SocketChannel."
+  [^SocketChannel ch]
+  (proxy [OutputStream] []
+    (write
+      ([b]
+       (if (bytes? b)
+         (.write ^OutputStream this ^bytes b 0 (alength ^bytes b))
+         (.write ^OutputStream this (byte-array [(unchecked-byte b)]) 0 1)))
+      ([^bytes bs off len]
+       (let [buf (ByteBuffer/wrap bs off len)]
+         (while (.hasRemaining buf)
+           (.write ch buf)))))))
+
 (defn stderr-thread
-  "Spawns a future which handles stderr from a process."
-  [^Process p running? node-id debug-buffer ^Writer log-writer log-stderr?]
+  "Spawns a future which handles stderr from a process. Takes the stream to
+  read from: in socket transport modes, that's stderr and stdout combined."
+  [^InputStream in running? node-id debug-buffer ^Writer log-writer log-stderr?]
   (io-thread running? node-id "stderr"
              [log log-writer]
-             [lines (bs/to-line-seq (.getErrorStream p))]
+             [lines (bs/to-line-seq in)]
              (when (seq lines)
                (let [^String line (first lines)]
                  ; Console log
@@ -134,47 +294,98 @@
                  (next lines)))))
 
 (defn stdout-thread
-  "Spawns a future which reads stdout from a process and inserts messages into
-  the network."
-  [^Process p running? node-id debug-buffer net]
-  (io-thread running? node-id "stdout"
-             []
-             [lines (bs/to-line-seq (.getInputStream p))]
-             (when (seq lines)
-               (let [line (first lines)]
-                 ; Parse and insert into network
-                 (try+ (let [parsed (parse-msg node-id line)]
-                         (net/send! net parsed)))
+  "Spawns a future which reads messages from a process and inserts them into
+  the network. Takes a function which returns the InputStream to read from:
+  the process's stdout, or its socket, and the codec messages are encoded
+  with."
+  [in codec running? node-id debug-buffer net]
+  (if (= :json codec)
+    (io-thread running? node-id "stdout"
+               []
+               [lines (bs/to-line-seq ^InputStream (in))]
+               (when (seq lines)
+                 (let [line (first lines)]
+                   ; Parse and insert into network. Messages to nodes which
+                   ; have left the cluster are lost.
+                   (try+ (let [parsed (parse-msg node-id line)]
+                           (net/send! net parsed))
+                         (catch [:type ::net/node-not-found] e
+                           nil))
 
-                 ; Debugging buffer
-                 (swap! debug-buffer conj line)
+                   ; Debugging buffer
+                   (swap! debug-buffer conj line)
 
-                 (next lines)))))
+                   (next lines))))
+    (io-thread running? node-id "stdout"
+               []
+               [s (DataInputStream. (BufferedInputStream. (in)))]
+               ; At the end of the stream, we're done. If the stream is cut
+               ; off mid-frame, or isn't framed at all, we can't find the next
+               ; frame, so that's the end too.
+               (when-let [frame (try (codec/read-frame! s)
+                                     (catch EOFException e
+                                       (warn node-id (.getMessage e))
+                                       nil)
+                                     (catch IOException e
+                                       ; The process crashed; see io-thread
+                                       nil)
+                                     (catch IllegalStateException e
+                                       (warn node-id "wrote something other"
+                                             "than a frame; ignoring the rest"
+                                             "of its output:" (.getMessage e))
+                                       nil))]
+                 ; Decode and insert into network. A frame we can't decode
+                 ; is logged, but we keep reading: frames are delimited by
+                 ; their lengths, so the next one is still intact.
+                 (try+ (let [parsed (parse-frame node-id codec frame)]
+                         ; Debugging buffer
+                         (swap! debug-buffer conj (pr-str parsed))
+                         (net/send! net parsed))
+                       (catch (comp #{:frame-not-valid :malformed-message}
+                                    :type) e
+                         (warn (:message &throw-context)))
+                       (catch [:type ::net/node-not-found] e
+                         nil))
+                 s))))
 
 (defn stdin-thread
   "Spawns a future which reads messages from the network and submits them to a
-  process's stdin."
-  [^Process p running? node-id net]
-  (io-thread running? node-id "stdin"
-             [w (OutputStreamWriter. (.getOutputStream p))]
-             [_ true]
-             (do (when-let [msg (net/recv! net node-id 1000)]
-                   (json/generate-stream msg w)
-                   (.write w "\n")
-                   (.flush w))
-                 ; We always recur; our input is unbounded.
-                 true)))
+  process. Takes a function which returns the OutputStream to write to: the
+  process's stdin, or its socket, and the codec to encode messages with."
+  [out codec running? node-id net]
+  (if (= :json codec)
+    (io-thread running? node-id "stdin"
+               [w (OutputStreamWriter. ^OutputStream (out))]
+               [_ true]
+               (do (when-let [msg (net/recv! net node-id 1000)]
+                     (json/generate-stream msg w)
+                     (.write w "\n")
+                     (.flush w))
+                   ; We always recur; our input is unbounded.
+                   true))
+    (io-thread running? node-id "stdin"
+               [w (DataOutputStream. (BufferedOutputStream. (out)))]
+               [_ true]
+               (do (when-let [msg (net/recv! net node-id 1000)]
+                     (codec/write-frame! w (codec/encode codec msg)))
+                   true))))
 
 (defn start-node!
   "Starts a node. Options:
 
       :dir          The directory to run in
+      :data-dir     A directory for the node's durable state, which we pass
+                    to the node in the MAELSTROM_DATA_DIR environment variable
       :bin          A program to run
       :args         A list of arguments to the program
       :node-id      This node's ID
       :log-file     A string file to receive stderr output
       :net          A network.
       :log-stderr?  Whether to log stderr output from processes to our logger
+      :transport    How to exchange messages: :stdio (the default), :tcp, or
+                    :unix
+      :codec        How to encode messages: :json (the default), :cbor, or
+                    :msgpack
 
   Returns:
 
@@ -183,20 +394,41 @@
    :stdin-thread    The future used for writing to the process' stdin
    :stdout-thread   The future used for stdout messages
    :stderr-thread   The future used for stderr messages
+   :listener        For socket transports, the listener from listen!
   "
   [opts]
   (info "launching" (:bin opts) (pr-str (:args opts)))
-  (let [node-id (:node-id opts)
-        net     (:net opts)
-        _       (net/add-node! net node-id)
-        _       (io/make-parents (:log-file opts))
-        log     (io/writer (:log-file opts))
-        bin     (.getCanonicalPath (io/file (:bin opts)))
-        process (-> (ProcessBuilder. ^java.util.List (cons bin (:args opts)))
-                    (.directory (io/file (:dir opts)))
-                    (.redirectOutput ProcessBuilder$Redirect/PIPE)
-                    (.redirectInput  ProcessBuilder$Redirect/PIPE)
-                    (.start))
+  (let [node-id   (:node-id opts)
+        net       (:net opts)
+        transport (:transport opts :stdio)
+        codec     (:codec opts :json)
+        _         (net/add-node! net node-id {:process? true})
+        _         (io/make-parents (:log-file opts))
+        ; Nodes may be restarted, so we append to their logs.
+        log       (io/writer (:log-file opts) :append true)
+        bin       (.getCanonicalPath (io/file (:bin opts)))
+        listener  (when-not (= :stdio transport)
+                    (listen! transport node-id))
+        builder   (-> (ProcessBuilder. ^java.util.List (cons bin (:args opts)))
+                      (.directory (io/file (:dir opts)))
+                      (.redirectOutput ProcessBuilder$Redirect/PIPE)
+                      (.redirectInput  ProcessBuilder$Redirect/PIPE))
+        _         (doto (.environment builder)
+                    (.put "MAELSTROM_CODEC" (name codec)))
+        _         (when-let [dir (:data-dir opts)]
+                    (.put (.environment builder) "MAELSTROM_DATA_DIR" dir))
+        _         (when listener
+                    (doto (.environment builder)
+                      (.put "MAELSTROM_TRANSPORT" (name transport))
+                      (.put "MAELSTROM_ADDRESS" (:address listener)))
+                    ; Stdout is just more logging now.
+                    (.redirectErrorStream builder true))
+        process   (.start builder)
+        [in out]  (if listener
+                    [#(channel-input-stream (await-conn listener))
+                     #(channel-output-stream (await-conn listener))]
+                    [#(.getInputStream process)
+                     #(.getOutputStream process)])
         running? (atom true)
         stdout-debug-buffer (atom (ring-buffer/ring-buffer debug-buffer-size))
         stderr-debug-buffer (atom (ring-buffer/ring-buffer debug-buffer-size))]
@@ -206,51 +438,86 @@
      :net           net
      :log           log
      :log-file      (:log-file opts)
+     :listener      listener
      :stderr-debug-buffer stderr-debug-buffer
      :stdout-debug-buffer stdout-debug-buffer
-     :stdin-thread  (stdin-thread  process running? node-id net)
-     :stderr-thread (stderr-thread process running? node-id stderr-debug-buffer
+     :stdin-thread  (stdin-thread out codec running? node-id net)
+     :stderr-thread (stderr-thread (if listener
+                                     (.getInputStream process)
+                                     (.getErrorStream process))
+                                   running? node-id stderr-debug-buffer
                                    log (:log-stderr? opts))
-     :stdout-thread (stdout-thread process running? node-id stdout-debug-buffer
-                                   net)}))
+     :stdout-thread (stdout-thread in codec running? node-id
+                                   stdout-debug-buffer net)}))
 
 (defn stop-node!
-  "Kills a node. Throws if the node already exited."
-  [{:keys [^Process process running? node-id net log-file ^Writer log
-           stdin-thread stderr-thread stdout-thread stderr-debug-buffer
-           stdout-debug-buffer]}]
-  (let [crashed? (not (.isAlive process))]
-    (when-not crashed?
-      ; Kill
-      (.. ^Process process destroyForcibly (waitFor 5 TimeUnit/SECONDS)))
-
-    ; Shut down workers
-    (reset! running? false)
-    (mapv deref [stdin-thread stderr-thread stdout-thread])
-
-    ; Remove self from network
-    (net/remove-node! net node-id)
-
-    ; Close log writer
-    (.close log)
-
-    ; If we crashed, throw a nice exception
-    (when crashed?
-      (throw+ {:type :node-crashed
-               :node node-id
-               :exit (.exitValue process)}
-              nil
-              (str "Node " node-id " crashed with exit status "
-                   (.exitValue process)
-                   ". Before crashing, it wrote to STDOUT:\n\n"
-                   (->> @stdout-debug-buffer (str/join "\n"))
-                   "\n\nAnd to STDERR:\n\n"
-                   (->> @stderr-debug-buffer (str/join "\n"))
-                   "\n\n"
-                   "Full STDERR logs are available in " log-file)))
-
-    ; Return status of workers
-    {:exit        (.exitValue process)
-     :stdin       @stdin-thread
-     :stderr      @stderr-thread
-     :stdout      @stdout-thread}))
+  "Kills a node. Throws if the node already exited. Options:
+
+      :leave-net?   If true, leaves the node's queue in the network, so that
+                    other nodes can keep sending it messages while it's down.
+                    Those messages are discarded when the node restarts."
+  ([node]
+   (stop-node! node {}))
+  ([{:keys [^Process process running? node-id net log-file ^Writer log
+            listener stdin-thread stderr-thread stdout-thread
+            stderr-debug-buffer stdout-debug-buffer]}
+    opts]
+   (let [crashed? (not (.isAlive process))]
+     (when-not crashed?
+       ; Kill
+       (.. ^Process process destroyForcibly (waitFor 5 TimeUnit/SECONDS)))
+
+     ; Shut down workers. Closing the listener unblocks any worker still
+     ; waiting for the node to connect, or reading from its socket.
+     (reset! running? false)
+     (when listener
+       (close-listener! listener))
+     (mapv deref [stdin-thread stderr-thread stdout-thread])
+
+     ; Remove self from network, or leave our queue there while we're down
+     (if (:leave-net? opts)
+       (net/down! net node-id)
+       (net/remove-node! net node-id))
+
+     ; Close log writer
+     (.close log)
+
+     ; If we crashed, throw a nice exception
+     (when crashed?
+       (throw+ {:type :node-crashed
+                :node node-id
+                :exit (.exitValue process)}
+               nil
+               (str "Node " node-id " crashed with exit status "
+                    (.exitValue process)
+                    ". Before crashing, it wrote to STDOUT:\n\n"
+                    (->> @stdout-debug-buffer (str/join "\n"))
+                    "\n\nAnd to STDERR:\n\n"
+                    (->> @stderr-debug-buffer (str/join "\n"))
+                    "\n\n"
+                    "Full STDERR logs are available in " log-file)))
+
+     ; Return status of workers
+     {:exit        (.exitValue process)
+      :stdin       @stdin-thread
+      :stderr      @stderr-thread
+      :stdout      @stdout-thread})))
+
+(defn signal!
+  "Sends a signal (e.g. \"STOP\") to a node's process, and any processes it
+  has spawned."
+  [{:keys [^Process process]} signal]
+  (let [handles (cons (.toHandle process)
+                      (iterator-seq (.iterator (.descendants process))))]
+    (doseq [^ProcessHandle h handles]
+      (sh "kill" (str "-" signal) (str (.pid h))))))
+
+(defn pause-node!
+  "Pauses a node's process with SIGSTOP."
+  [node]
+  (signal! node "STOP"))
+
+(defn resume-node!
+  "Resumes a paused node's process with SIGCONT."
+  [node]
+  (signal! node "CONT"))
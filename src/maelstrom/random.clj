@@ -0,0 +1,80 @@
+(ns maelstrom.random
+  "Maelstrom's source of randomness. Every random choice Maelstrom makes--
+  latency draws, packet loss, tampering, which replica a service talks to,
+  the operations its generators emit and how far apart, and which node a
+  nemesis targets--comes from here, and is seeded by `--seed`.
+
+  Code which draws from more than one thread would see draws interleave
+  however those threads happen to run. Instead, each drawer gets its own
+  stream, derived from the root seed and a key using `rng`, and binds it to
+  `*rng*`: each sender in the network, each service, the nemesis, and the
+  test's generator (see maelstrom.generator). With `--virtual-time`, the
+  order in which messages are delivered is fixed too; see maelstrom.net.
+
+  What a seed can't fix is time. Nodes are real processes, and clients and
+  the nemesis act in real time, so when operations complete, and thus which
+  ones overlap, can differ between runs. Jepsen's own kill and pause nemeses
+  also pick their targets with clojure.core's RNG, which we leave alone."
+  (:refer-clojure :exclude [rand rand-int rand-nth shuffle])
+  (:import (java.util ArrayList
+                      Collection
+                      Collections
+                      Random)))
+
+(def root-seed
+  "The seed from which all of Maelstrom's randomness is derived."
+  (atom (.nextLong (Random.))))
+
+(def ^:dynamic ^Random *rng*
+  "The RNG used by rand, rand-int, and friends. Bind this to draw from a
+  particular stream."
+  (Random. @root-seed))
+
+(defn rng
+  "Constructs a new Random whose sequence is determined entirely by the root
+  seed and the given key (e.g. a node ID)."
+  [k]
+  (Random. (hash [@root-seed k])))
+
+(defmacro with-rng
+  "Evaluates body with *rng* bound to the given Random."
+  [rng & body]
+  `(binding [*rng* ~rng]
+     ~@body))
+
+(defn rand
+  "Like clojure.core/rand, but draws from *rng*."
+  ([]
+   (.nextDouble *rng*))
+  ([n]
+   (* n (rand))))
+
+(defn gaussian
+  "A normally distributed double with mean 0 and standard deviation 1, drawn
+  from *rng*."
+  []
+  (.nextGaussian *rng*))
+
+(defn rand-int
+  "Like clojure.core/rand-int, but draws from *rng*."
+  [n]
+  (int (rand n)))
+
+(defn rand-nth
+  "Like clojure.core/rand-nth, but draws from *rng*."
+  [coll]
+  (nth coll (rand-int (count coll))))
+
+(defn shuffle
+  "Like clojure.core/shuffle, but draws from *rng*."
+  [^Collection coll]
+  (let [al (ArrayList. coll)]
+    (Collections/shuffle al *rng*)
+    (vec al)))
+
+(defn seed!
+  "Resets the root seed, and with it every stream Maelstrom draws from."
+  [seed]
+  (reset! root-seed seed)
+  (alter-var-root #'*rng* (constantly (Random. seed)))
+  seed)
@@ -10,11 +10,18 @@
   per-key kv store--say, several distinct Raft groups, one per shard. In
   Maelstrom, you'd write your nodes to accept transaction requests, then (in
   accordance with your chosen transaction protocol) make your own key-value
-  requests to the `lin-kv` service."
+  requests to the `lin-kv` service.
+
+  The RPCs below are the APIs of the queue, lock, disk, and time services;
+  see doc/services.md for details."
+  (:refer-clojure :exclude [peek])
   (:require [amalloy.ring-buffer :as ring-buffer]
             [clojure.tools.logging :refer [info warn]]
-            [maelstrom [net :as net]]
-            [jepsen [util :as util]]))
+            [maelstrom [client :as c]
+                       [net :as net]
+                       [random :as r]]
+            [jepsen [util :as util]]
+            [schema.core :as s]))
 
 (defprotocol PersistentService
   (handle [this message]
@@ -131,6 +138,62 @@
   []
   (PersistentTSO. 0))
 
+(c/defrpc enqueue!
+  "Adds a value to the end of the queue identified by `key`, creating that
+  queue if it doesn't exist."
+  {:type  (s/eq "enqueue")
+   :key   s/Any
+   :value s/Any}
+  {:type  (s/eq "enqueue_ok")})
+
+(c/defrpc dequeue!
+  "Removes the value at the front of the queue identified by `key`, and
+  returns it. Returns error 20 if the queue is empty."
+  {:type  (s/eq "dequeue")
+   :key   s/Any}
+  {:type  (s/eq "dequeue_ok")
+   :value s/Any})
+
+(c/defrpc peek
+  "Returns the value at the front of the queue identified by `key`, without
+  removing it. Returns error 20 if the queue is empty."
+  {:type  (s/eq "peek")
+   :key   s/Any}
+  {:type  (s/eq "peek_ok")
+   :value s/Any})
+
+; qs is a map of keys to clojure.lang.PersistentQueues. We never keep empty
+; queues around, so that equal contents mean equal services.
+(defrecord FIFOQueue [qs]
+  PersistentService
+  (handle [this message]
+    (let [body  (:body message)
+          k     (:key body)
+          q     (get qs k)
+          empty {:type "error", :code 20, :text "queue is empty"}]
+      (case (:type body)
+        "enqueue" [(FIFOQueue.
+                     (assoc qs k (conj (or q clojure.lang.PersistentQueue/EMPTY)
+                                       (:value body))))
+                   {:type "enqueue_ok"}]
+        "dequeue" (if q
+                    [(FIFOQueue. (let [q' (pop q)]
+                                   (if (seq q')
+                                     (assoc qs k q')
+                                     (dissoc qs k))))
+                     {:type "dequeue_ok", :value (clojure.core/peek q)}]
+                    [this empty])
+        "peek"    [this
+                   (if q
+                     {:type "peek_ok", :value (clojure.core/peek q)}
+                     empty)]))))
+
+(defn persistent-queue
+  "A collection of FIFO queues, identified by keys. Responds to `enqueue`,
+  `dequeue`, and `peek` requests."
+  []
+  (FIFOQueue. {}))
+
 (defprotocol MutableService
   (handle! [this message]
            "Handles a message, possibly mutating this service and returning a
@@ -169,7 +232,7 @@
                      ; Pick some index to interact with
                      index        (-> last-index
                                       (- client-index)
-                                      rand-int
+                                      r/rand-int
                                       (+ client-index))
                      _ (assert (<= client-index index last-index))
                      ; We compute a negative offset into the buffer: -1 is
@@ -218,14 +281,14 @@
              (fn [replicas]
                ; Merge one random replica into another
                (let [n            (count replicas)
-                     merge-source (rand-int n)
-                     merge-dest   (rand-int n)
+                     merge-source (r/rand-int n)
+                     merge-dest   (r/rand-int n)
                      merged       (merge-services (nth replicas merge-source)
                                                   (nth replicas merge-dest))
                      replicas'    (assoc replicas merge-dest merged)
 
                      ; Apply message to yet another random replica
-                     i              (rand-int n)
+                     i              (r/rand-int n)
                      [replica' res] (handle (nth replicas i) message)
                      replicas'      (assoc replicas i replica')]
                  (reset! response res)
@@ -241,25 +304,357 @@
   ([n persistent-service]
    (Eventual. (atom (vec (repeat n persistent-service))))))
 
+(defprotocol Storage
+  "Services which act as a node's local storage, and can lose data when that
+  node crashes."
+  (crash! [this node]
+          "Simulates a crash of the given node: discards everything it wrote,
+          but never synced.")
+  (wipe! [this node]
+         "Simulates the given node losing its storage entirely."))
+
+(c/defrpc disk-read
+  "Reads `key` from the requesting node's own disk, returning the node's
+  latest write to it, whether or not that write has been synced. Returns
+  error 20 if the key has never been written, or the write was lost."
+  {:type  (s/eq "read")
+   :key   s/Any}
+  {:type  (s/eq "read_ok")
+   :value s/Any})
+
+(c/defrpc disk-write!
+  "Writes `value` to `key` on the requesting node's own disk. The write is
+  buffered, and lost if the node crashes before it syncs."
+  {:type  (s/eq "write")
+   :key   s/Any
+   :value s/Any}
+  {:type  (s/eq "write_ok")})
+
+(c/defrpc disk-sync!
+  "Makes every write the requesting node has made to its disk so far durable,
+  so that it survives a crash."
+  {:type  (s/eq "sync")}
+  {:type  (s/eq "sync_ok")})
+
+; Disks is an atom of a map of node IDs to {:synced {k v}, :unsynced {k v}}.
+; Each node sees only its own disk.
+(defrecord Disk [disks]
+  MutableService
+  (handle! [this message]
+    (let [node (:src message)
+          body (:body message)
+          k    (:key body)]
+      (case (:type body)
+        "read"  (let [{:keys [synced unsynced]} (get @disks node)]
+                  (cond (contains? unsynced k)
+                        {:type "read_ok", :value (get unsynced k)}
+
+                        (contains? synced k)
+                        {:type "read_ok", :value (get synced k)}
+
+                        :else
+                        {:type "error", :code 20, :text "key does not exist"}))
+        "write" (do (swap! disks assoc-in [node :unsynced k] (:value body))
+                    {:type "write_ok"})
+        "sync"  (do (swap! disks update node
+                           (fn [{:keys [synced unsynced]}]
+                             {:synced   (merge synced unsynced)
+                              :unsynced {}}))
+                    {:type "sync_ok"}))))
+
+  Storage
+  (crash! [this node]
+    (swap! disks update node assoc :unsynced {}))
+
+  (wipe! [this node]
+    (swap! disks dissoc node)))
+
+(defn disk
+  "A simulated local disk for each node. Nodes write keys, which are buffered
+  until the node sends a sync request. When a node crashes, its unsynced
+  writes are lost."
+  []
+  (Disk. (atom {})))
+
+(defprotocol Skew
+  "Services which give each node its own, possibly wrong, clock."
+  (bump-clock! [this node delta]
+               "Jumps a node's clock forward (or back) by delta ms.")
+  (drift-clock! [this node rate]
+                "Makes a node's clock run at rate times real time; e.g. 1.1 for
+                10% fast.")
+  (strobe-clock! [this node delta period duration]
+                 "For the next duration ms, flips a node's clock back and forth
+                 by delta ms, every period ms.")
+  (reset-clock! [this node]
+                "Puts a node's clock back in sync with real time.")
+  (clock-offsets [this]
+                 "A map of nodes to how far, in ms, their clocks are from real
+                 time."))
+
+(def default-clock
+  "A clock which agrees with real time."
+  {:offset 0, :rate 1, :since 0, :strobe nil})
+
+(defn skewed-time
+  "What time does a clock read, given the real time now, in ms?"
+  [{:keys [offset rate since strobe]} now]
+  (let [t (+ now offset (long (* (- now since) (dec rate))))]
+    (if (and strobe
+             (< now (:until strobe))
+             (odd? (quot (- now (:since strobe)) (:period strobe))))
+      (+ t (:delta strobe))
+      t)))
+
+(defn rebase-clock
+  "Folds a clock's accumulated drift into its offset, as of now, so that we can
+  change its rate without a discontinuity."
+  [clock now]
+  (assoc clock
+         :offset (- (skewed-time (dissoc clock :strobe) now) now)
+         :since  now))
+
+(c/defrpc current-time
+  "Returns the current `time`, in milliseconds since the epoch, according to
+  the requesting node's own clock. Nodes' clocks agree with real time until
+  the clock nemesis skews them."
+  {:type  (s/eq "time")}
+  {:type  (s/eq "time_ok")
+   :time  s/Int})
+
+; Clocks is an atom of a map of node IDs to clock maps, like default-clock.
+; Now is a function which returns the real time, in ms.
+(defrecord Clock [clocks now]
+  MutableService
+  (handle! [this message]
+    (case (:type (:body message))
+      "time" {:type "time_ok"
+              :time (skewed-time (get @clocks (:src message) default-clock)
+                                 (now))}))
+
+  Skew
+  (bump-clock! [this node delta]
+    (swap! clocks update node
+           (fn [clock]
+             (update (or clock default-clock) :offset + delta))))
+
+  (drift-clock! [this node rate]
+    (let [now (now)]
+      (swap! clocks update node
+             (fn [clock]
+               (-> (or clock default-clock)
+                   (rebase-clock now)
+                   (assoc :rate rate))))))
+
+  (strobe-clock! [this node delta period duration]
+    (let [now (now)]
+      (swap! clocks update node
+             (fn [clock]
+               (assoc (or clock default-clock)
+                      :strobe {:delta  delta
+                               :period period
+                               :since  now
+                               :until  (+ now duration)})))))
+
+  (reset-clock! [this node]
+    (swap! clocks dissoc node))
+
+  (clock-offsets [this]
+    (let [now (now)]
+      (->> @clocks
+           (map (fn [[node clock]] [node (- (skewed-time clock now) now)]))
+           (into (sorted-map))))))
+
+(defn clock
+  "A clock service which tells each node the time, in milliseconds since the
+  epoch, according to that node's own clock. Nodes request `{:type
+  \"time\"}`, and receive `{:type \"time_ok\", :time 1234}`. Clocks agree
+  with real time until skewed; see Skew. Optionally takes a function which
+  returns the real time in ms, like net/now-millis; by default,
+  System/currentTimeMillis."
+  ([]
+   (clock #(System/currentTimeMillis)))
+  ([now]
+   (Clock. (atom {}) now)))
+
+(defn real-time
+  "What's the real time, in ms, according to a Clock service's time source?
+  Takes nil for System/currentTimeMillis."
+  [clock]
+  (if clock
+    ((:now clock))
+    (System/currentTimeMillis)))
+
+(defn local-time
+  "What time, in ms, does a node's clock read when the real time is now? Takes
+  a Clock service, or nil for real time."
+  [clock node now]
+  (if clock
+    (skewed-time (get @(:clocks clock) node default-clock) now)
+    now))
+
+(c/defrpc acquire!
+  "Requests the lock identified by `key`, for a lease of `ttl` milliseconds. If
+  nobody holds the lock, or the holder's lease has expired, grants the lock,
+  returning a new fencing `token`, and `expires_at`: when the lease ends,
+  according to the requesting node's clock (see the `time` service). If
+  someone else (or you!) holds an unexpired lease, returns error 11."
+  {:type  (s/eq "acquire")
+   :key   s/Any
+   :ttl   s/Int}
+  {:type       (s/eq "acquire_ok")
+   :token      s/Int
+   :expires_at s/Int})
+
+(c/defrpc renew!
+  "Extends a lease on the lock identified by `key` to `ttl` milliseconds from
+  now. `token` must be the token of the current, unexpired lease; otherwise,
+  returns error 22. Returns the new `expires_at`, by the requesting node's
+  clock."
+  {:type  (s/eq "renew")
+   :key   s/Any
+   :token s/Int
+   :ttl   s/Int}
+  {:type       (s/eq "renew_ok")
+   :expires_at s/Int})
+
+(c/defrpc release!
+  "Releases the lock identified by `key`. `token` must be the token of the
+  current, unexpired lease; otherwise, returns error 22."
+  {:type  (s/eq "release")
+   :key   s/Any
+   :token s/Int}
+  {:type  (s/eq "release_ok")})
+
+; State is an atom of {:next-token n, :locks {key {:holder node, :token n,
+; :expires real-ms}}}. Leases expire by the clock's real time, but we tell
+; each node when its lease expires according to its own clock: a node with a
+; slow clock believes its lease lasts longer than it does.
+(defrecord Locks [state clock]
+  MutableService
+  (handle! [this message]
+    (let [node     (:src message)
+          body     (:body message)
+          k        (:key body)
+          now      (real-time clock)
+          response (atom nil)
+          reply!   (fn [body] (reset! response body))
+          fail!    (fn [code text]
+                     (reply! {:type "error", :code code, :text text}))]
+      (swap! state
+             (fn [{:keys [next-token locks] :as state}]
+               (let [lock (get locks k)
+                     lock (when (and lock (< now (:expires lock))) lock)
+                     held? (and lock (= (:token body) (:token lock)))]
+                 (case (:type body)
+                   "acquire"
+                   (if lock
+                     (do (fail! 11 (str "lock is held by " (:holder lock)))
+                         state)
+                     (let [expires (+ now (:ttl body))]
+                       (reply! {:type       "acquire_ok"
+                                :token      next-token
+                                :expires_at (local-time clock node expires)})
+                       {:next-token (inc next-token)
+                        :locks      (assoc locks k {:holder  node
+                                                    :token   next-token
+                                                    :expires expires})}))
+
+                   "renew"
+                   (if held?
+                     (let [expires (+ now (:ttl body))]
+                       (reply! {:type       "renew_ok"
+                                :expires_at (local-time clock node expires)})
+                       (assoc-in state [:locks k :expires] expires))
+                     (do (fail! 22 "token does not hold an unexpired lease")
+                         state))
+
+                   "release"
+                   (if held?
+                     (do (reply! {:type "release_ok"})
+                         (update state :locks dissoc k))
+                     (do (fail! 22 "token does not hold an unexpired lease")
+                         state))))))
+      @response)))
+
+(defn lock-service
+  "A linearizable lock service. Leases expire by the real time of the given
+  Clock service (or System/currentTimeMillis, if nil); expiry times are
+  reported by each node's own clock, so clock skew can make a node think it
+  still holds a lease which has expired."
+  ([]
+   (lock-service nil))
+  ([clock]
+   (Locks. (atom {:next-token 0, :locks {}}) clock)))
+
+(def service-rpcs
+  "A map of service node IDs to the names of the RPCs in this namespace which
+  that service serves. Requests to these services are checked against those
+  RPCs' schemas; other services (e.g. lin-kv) check their own requests."
+  {"lin-queue" '#{enqueue! dequeue! peek}
+   "seq-queue" '#{enqueue! dequeue! peek}
+   "lin-lock"  '#{acquire! renew! release!}
+   "disk"      '#{disk-read disk-write! disk-sync!}
+   "time"      '#{current-time}})
+
+(def request-checkers
+  "A delay of a map of service node IDs to maps of request :types to schema
+  checkers, built from the RPCs in service-rpcs."
+  (delay
+    (let [rpcs (->> @c/rpc-registry
+                    (filter (comp #{'maelstrom.service} ns-name :ns))
+                    (map (juxt :name :send))
+                    (into {}))]
+      (->> service-rpcs
+           (map (fn [[node-id names]]
+                  [node-id
+                   (->> names
+                        (map (fn [rpc-name]
+                               (let [send (rpcs rpc-name)]
+                                 [(-> send s/explain :type second)
+                                  (s/checker
+                                    (-> send
+                                        (dissoc :msg_id)
+                                        (assoc (s/optional-key :msg_id)
+                                               s/Int)))])))
+                        (into {}))]))
+           (into {})))))
+
+(defn check-request
+  "Takes a service's node ID and a request body sent to it. If the service
+  serves one of our RPCs of that type, and the body doesn't match that RPC's
+  schema, returns a malformed-request error body. Otherwise, nil."
+  [node-id body]
+  (when-let [checker (get-in @request-checkers [node-id (:type body)])]
+    (when-let [errs (checker body)]
+      {:type "error"
+       :code (:code c/malformed-request)
+       :text (str "malformed request: " (pr-str errs))})))
+
 (defn service-thread
   "Spawns a thread which handles service requests from the network. Takes a
-  network, a running atom, a node ID, and a MutableService."
+  network, a running atom, a node ID, and a MutableService. Each service draws
+  its random choices from its own stream, derived from the test seed.
+  Malformed requests are rejected before they reach the service; see
+  check-request."
   [net node-id service running?]
   (future
     (util/with-thread-name (str "maelstrom " node-id)
-      (while @running?
-        (try
-          (when-let [message (net/recv! net node-id 1000)]
-            (let [body (assoc (handle! service message)
-                              :in_reply_to (:msg_id (:body message)))]
-              (net/send! net {:src  node-id
-                              :dest (:src message)
-                              :body body})))
-          (catch InterruptedException e
-            ; We're aborting
-            )
-          (catch Exception e
-            (warn e "Error in service worker!")))))))
+      (r/with-rng (r/rng node-id)
+        (while @running?
+          (try
+            (when-let [message (net/recv! net node-id 1000)]
+              (let [body (assoc (or (check-request node-id (:body message))
+                                    (handle! service message))
+                                :in_reply_to (:msg_id (:body message)))]
+                (net/send! net {:src  node-id
+                                :dest (:src message)
+                                :body body})))
+            (catch InterruptedException e
+              ; We're aborting
+              )
+            (catch Exception e
+              (warn e "Error in service worker!"))))))))
 
 (defn start-services!
   "Takes a network and a map of node ids to MutableServices. Spawns threads
@@ -287,9 +682,15 @@
   (:services services))
 
 (defn default-services
-  "Constructs some default services you might find useful."
-  [test]
-  {"lww-kv"  (eventual     (lww-kv))
-   "seq-kv"  (sequential   (persistent-kv))
-   "lin-kv"  (linearizable (persistent-kv))
-   "lin-tso" (linearizable (persistent-tso))})
+  "Constructs some default services you might find useful. Takes a Clock
+  service, which serves `time`, and which the lock service reads."
+  [test clock]
+  {"lww-kv"    (eventual     (lww-kv))
+   "seq-kv"    (sequential   (persistent-kv))
+   "lin-kv"    (linearizable (persistent-kv))
+   "lin-tso"   (linearizable (persistent-tso))
+   "seq-queue" (sequential   (persistent-queue))
+   "lin-queue" (linearizable (persistent-queue))
+   "lin-lock"  (lock-service clock)
+   "time"      clock
+   "disk"      (disk)})
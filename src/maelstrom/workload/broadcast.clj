@@ -1,16 +1,25 @@
 (ns maelstrom.workload.broadcast
   "A broadcast system. Essentially a test of eventually-consistent set
   addition, but also provides an initial `topology` message to the cluster with
-  a set of neighbors for each node to use."
+  a set of neighbors for each node to use.
+
+  With --topology-change-interval, Maelstrom also changes the topology during
+  the test, adding or removing a link, or rearranging the whole network, and
+  sends every node a fresh `topology` message. Topologies always stay
+  connected, so nodes should still converge."
   (:refer-clojure :exclude [read])
-  (:require [clojure.pprint :refer [pprint]]
+  (:require [cheshire.core :as json]
+            [clojure.pprint :refer [pprint]]
             [clojure.tools.logging :refer [info warn]]
             [clojure.zip :as zip]
             [maelstrom [client :as c]
-                       [net :as net]]
+                       [generator :as mgen]
+                       [net :as net]
+                       [random :as r]]
             [jepsen [checker :as checker]
                     [client :as client]
-                    [generator :as gen]]
+                    [generator :as gen]
+                    [util :as util]]
             [knossos.op :as op]
             [schema.core :as s]
             [slingshot.slingshot :refer [try+ throw+]]))
@@ -18,7 +27,9 @@
 (c/defrpc topology!
   "A topology message is sent at the start of the test, after initialization,
   and informs the node of an optional network topology to use for broadcast.
-  The topology consists of a map of node IDs to lists of neighbor node IDs."
+  The topology consists of a map of node IDs to lists of neighbor node IDs.
+  If the topology changes during the test, nodes receive new topology
+  messages, each of which replaces the last."
   {:type      (s/eq "topology")
    :topology  {net/NodeId [net/NodeId]}}
   {:type      (s/eq "topology_ok")})
@@ -165,39 +176,250 @@
             (recur (zip/next loc)
                    (assoc neighbors me my-neighbors))))))))
 
+(defn edges->topology
+  "Takes a collection of nodes and a collection of [a b] edges between them,
+  and builds an undirected topology map. Every node appears, even if it has
+  no neighbors."
+  [nodes edges]
+  (let [empty (zipmap nodes (repeat (sorted-set)))]
+    (->> edges
+         (remove (fn [[a b]] (= a b)))
+         (reduce (fn [topo [a b]]
+                   (-> topo
+                       (update a conj b)
+                       (update b conj a)))
+                 empty)
+         (map (fn [[node neighbors]] [node (vec neighbors)]))
+         (into {}))))
+
+(defn ring-topology
+  "Nodes are arranged in a circle, each connected to the nodes on either
+  side."
+  [test]
+  (let [nodes (vec (:nodes test))
+        n     (count nodes)]
+    (edges->topology nodes
+                     (for [i (range n)]
+                       [(nodes i) (nodes (mod (inc i) n))]))))
+
+(defn star-topology
+  "The first node is connected to every other node, and no others are
+  connected."
+  [test]
+  (let [[hub & spokes :as nodes] (:nodes test)]
+    (edges->topology nodes (map (partial vector hub) spokes))))
+
+(defn hypercube-topology
+  "Nodes are the corners of a hypercube: node i is connected to every node
+  whose index differs from i by a single bit. If the number of nodes isn't a
+  power of two, the missing corners are left out, which still leaves the
+  graph connected."
+  [test]
+  (let [nodes (vec (:nodes test))
+        n     (count nodes)]
+    (edges->topology nodes
+                     (for [i   (range n)
+                           bit (take-while #(< % n) (iterate (partial * 2) 1))
+                           :let [j (bit-xor i bit)]
+                           :when (< j n)]
+                       [(nodes i) (nodes j)]))))
+
+(defn connected?
+  "Is every node in a topology reachable from every other?"
+  [topo]
+  (let [nodes (set (keys topo))]
+    (loop [seen  #{}
+           stack (vec (take 1 nodes))]
+      (if-let [node (peek stack)]
+        (if (seen node)
+          (recur seen (pop stack))
+          (recur (conj seen node) (into (pop stack) (get topo node))))
+        (= nodes seen)))))
+
+(def random-topology-attempts
+  "How many times do we try to generate a random, connected topology before
+  giving up?"
+  1000)
+
+(defn random-connected-topology
+  "Calls (gen) until it returns a connected topology, and returns that
+  topology. If we can't find one after a while, returns (fallback)."
+  [gen fallback]
+  (or (->> (repeatedly random-topology-attempts gen)
+           (filter (fn [topo] (and topo (connected? topo))))
+           first)
+      (fallback)))
+
+(defn k-regular-topology
+  "A random graph where every node has k neighbors, built by pairing up k
+  stubs per node at random. If n * k is odd, one node gets k - 1 neighbors."
+  [k test]
+  (let [nodes (vec (:nodes test))
+        n     (count nodes)]
+    (if (<= n (inc k))
+      (total-topology test)
+      (random-connected-topology
+        (fn gen []
+          (let [pairs (->> (mapcat (partial repeat k) nodes)
+                           r/shuffle
+                           (partition 2))]
+            ; Self-loops and duplicate edges would leave some nodes short of
+            ; k neighbors; try again.
+            (when (and (not-any? (fn [[a b]] (= a b)) pairs)
+                       (apply distinct? (map set pairs)))
+              (edges->topology nodes pairs))))
+        (partial ring-topology test)))))
+
+(defn small-world-topology
+  "A Watts-Strogatz small-world graph: nodes in a ring, each connected to its
+  k nearest neighbors, with each edge rewired to a random node with
+  probability p. Short paths, but mostly local links."
+  [k p test]
+  (let [nodes (vec (:nodes test))
+        n     (count nodes)
+        ; A ring lattice
+        lattice (for [i (range n)
+                      j (range 1 (inc (quot k 2)))
+                      :when (< j n)]
+                  [i (mod (+ i j) n)])]
+    (random-connected-topology
+      (fn gen []
+        (->> lattice
+             (reduce (fn [edges [i j]]
+                       (if (< (r/rand) p)
+                         (let [taken (->> edges
+                                          (keep (fn [[a b]]
+                                                  (cond (= a i) b
+                                                        (= b i) a)))
+                                          set)
+                               free  (remove (conj taken i) (range n))]
+                           (if (seq free)
+                             (conj edges [i (r/rand-nth (vec free))])
+                             (conj edges [i j])))
+                         (conj edges [i j])))
+                     #{})
+             (map (fn [[i j]] [(nodes i) (nodes j)]))
+             (edges->topology nodes)))
+      (fn fallback []
+        (edges->topology nodes (map (fn [[i j]] [(nodes i) (nodes j)])
+                                    lattice))))))
+
 (def topologies
   "A map of topology names to functions which generate those topologies, given
   a test."
-  {:line   line-topology
-   :grid   grid-topology
-   :tree   (partial tree-topology 2)
-   :tree2  (partial tree-topology 2)
-   :tree3  (partial tree-topology 3)
-   :tree4  (partial tree-topology 4)
-   :total  total-topology})
+  {:line        line-topology
+   :grid        grid-topology
+   :ring        ring-topology
+   :star        star-topology
+   :hypercube   hypercube-topology
+   :k-regular   (partial k-regular-topology 3)
+   :small-world (partial small-world-topology 4 0.2)
+   :tree        (partial tree-topology 2)
+   :tree2       (partial tree-topology 2)
+   :tree3       (partial tree-topology 3)
+   :tree4       (partial tree-topology 4)
+   :total       total-topology})
+
+(defn load-topology-file
+  "Reads a topology from a JSON file containing an adjacency map, like
+  {\"n0\": [\"n1\"], \"n1\": [\"n0\"]}. Throws if the file isn't shaped like
+  that."
+  [file]
+  (let [topo (json/parse-string (slurp file))]
+    (when-not (and (map? topo)
+                   (every? (fn [[node neighbors]]
+                             (and (sequential? neighbors)
+                                  (every? string? neighbors)))
+                           topo))
+      (throw (IllegalArgumentException.
+               (str "Topology file " file " should contain a JSON object "
+                    "mapping node IDs to arrays of neighbor IDs"))))
+    (->> topo
+         (map (fn [[node neighbors]] [node (vec neighbors)]))
+         (into {}))))
 
 (defn topology
   "Computes a topology map for the test: a map of nodes to the nodes which are
-  their immediate neighbors. Uses the :topology keyword in the test map."
+  their immediate neighbors. Uses the :topology-file map in the test, if
+  present, and otherwise the :topology keyword."
   [test]
-  (let [topo-fn (-> test :topology topologies)]
-    (topo-fn test)))
+  (or (:topology-file test)
+      (let [topo-fn (-> test :topology topologies)]
+        (topo-fn test))))
+
+(defn links
+  "All the links in a topology, as sorted [a b] pairs."
+  [topo]
+  (->> topo
+       (mapcat (fn [[a bs]] (map (fn [b] (vec (sort [a b]))) bs)))
+       distinct
+       sort))
+
+(defn add-link
+  "Connects two nodes in a topology."
+  [topo [a b]]
+  (-> topo
+      (update a (comp vec distinct conj) b)
+      (update b (comp vec distinct conj) a)))
+
+(defn remove-link
+  "Disconnects two nodes in a topology."
+  [topo [a b]]
+  (-> topo
+      (update a (comp vec (partial remove #{b})))
+      (update b (comp vec (partial remove #{a})))))
+
+(defn change-topology
+  "Takes a test and a topology, and returns a [change topology'] pair, where
+  change describes how the topology changed: [:add-link a b], [:remove-link a
+  b], or [:reshape topology-name]. Never disconnects the topology."
+  [test topo]
+  (let [nodes     (:nodes test)
+        removable (->> (links topo)
+                       (filter (comp connected? (partial remove-link topo))))
+        missing   (->> (for [a nodes, b nodes :when (neg? (compare a b))]
+                         [a b])
+                       (remove (set (links topo))))
+        kinds     (cond-> [:reshape]
+                    (seq removable) (conj :remove-link)
+                    (seq missing)   (conj :add-link))]
+    (case (r/rand-nth kinds)
+      :add-link    (let [link (r/rand-nth (vec missing))]
+                     [(into [:add-link] link) (add-link topo link)])
+      :remove-link (let [link (r/rand-nth (vec removable))]
+                     [(into [:remove-link] link) (remove-link topo link)])
+      ; Every topology function is connected; shuffling nodes gives us a
+      ; different arrangement of the same shape.
+      :reshape     (let [topo-name (r/rand-nth (sort (keys topologies)))
+                         topo-fn   (topologies topo-name)]
+                     [[:reshape topo-name]
+                      (topo-fn (assoc test :nodes (r/shuffle nodes)))]))))
 
 (defn client
   ([net]
-   (client net nil nil))
-  ([net conn node]
+   (client net nil nil (atom nil)))
+  ([net conn node topo]
    (reify client/Client
      (open! [this test node]
-       (client net (c/open! net) node))
+       (client net (c/open! net) node topo))
 
      (setup! [this test]
-       (let [topo (topology test)]
-         (topology! conn node {:type :topology, :topology topo})))
+       (let [t (swap! topo #(or % (topology test)))]
+         (topology! conn node {:type :topology, :topology t})))
 
      (invoke! [_ test op]
        (c/with-errors op #{:read}
          (case (:f op)
+           ; Change the topology, and tell every node about it. We hold the
+           ; lock while sending, so nodes see changes in order.
+           :topology
+           (locking topo
+             (let [[change t] (change-topology test @topo)]
+               (reset! topo t)
+               (doseq [n (:nodes test)]
+                 (topology! conn n {:type :topology, :topology t}))
+               (assoc op :type :ok, :value {:change change, :topology t})))
+
            :broadcast
            (do (broadcast! conn node {:type :broadcast, :message (:value op)})
                (assoc op :type :ok))
@@ -213,28 +435,48 @@
        (c/close! conn)))))
 
 (defn checker
-  "This is exactly a set-full checker, but with :add mapped to :broadcast."
+  "This is exactly a set-full checker, but with :add mapped to :broadcast.
+  Also reports the :topology-changes made during the test: their times, in
+  seconds, and what changed."
   []
   (reify checker/Checker
     (check [this test history opts]
-      (checker/check (checker/set-full)
-                     test
-                     (mapv (fn [op]
-                             (if (= :broadcast (:f op))
-                               (assoc op :f :add)
-                               op))
-                           history)
-                     opts))))
+      (let [topo?   (comp #{:topology} :f)
+            changes (->> history
+                         (filter topo?)
+                         (filter op/ok?)
+                         (mapv (fn [op]
+                                 {:time   (util/nanos->secs (:time op))
+                                  :change (:change (:value op))})))]
+        (cond-> (checker/check (checker/set-full)
+                               test
+                               (->> history
+                                    (remove topo?)
+                                    (mapv (fn [op]
+                                            (if (= :broadcast (:f op))
+                                              (assoc op :f :add)
+                                              op))))
+                               opts)
+          (seq changes) (assoc :topology-changes changes))))))
 
 (defn workload
   "Constructs a workload for a broadcast protocol, given options from the CLI
   test constructor:
 
-      {:net     A Maelstrom network}"
+      {:net                       A Maelstrom network
+       :topology-change-interval  Seconds between topology changes, on
+                                  average, or nil for a fixed topology}"
   [opts]
   {:client          (client (:net opts))
-   :generator       (gen/mix [(->> (range)
-                                   (map (fn [x] {:f :broadcast, :value x})))
-                              (repeat {:f :read})])
+   :generator       (let [ops (mgen/mix [(->> (range)
+                                              (map (fn [x]
+                                                     {:f     :broadcast
+                                                      :value x})))
+                                         (repeat {:f :read})])]
+                      (if-let [interval (:topology-change-interval opts)]
+                        (gen/any ops
+                                 (mgen/stagger interval
+                                               (repeat {:f :topology})))
+                        ops))
    :final-generator (gen/each-thread {:f :read, :final? true})
    :checker         (checker)})
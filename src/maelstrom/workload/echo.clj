@@ -2,7 +2,8 @@
   "A simple echo workload: sends a message, and expects to get that same
   message back."
   (:require [maelstrom [client :as c]
-                       [net :as net]]
+                       [net :as net]
+                       [random :as r]]
             [jepsen [checker :as checker]
                     [client :as client]
                     [generator :as gen]
@@ -71,6 +72,6 @@
   {:client    (client (:net opts))
    :generator (->> (fn []
                      {:f      :echo
-                      :value  (str "Please echo " (rand-int 128))})
+                      :value  (str "Please echo " (r/rand-int 128))})
                    (gen/each-thread))
    :checker   (checker)})
@@ -3,6 +3,7 @@
   current value of the set."
   (:refer-clojure :exclude [read])
   (:require [maelstrom [client :as c]
+                       [generator :as mgen]
                        [net :as net]]
             [jepsen [checker :as checker]
                     [client :as client]
@@ -56,7 +57,7 @@
       {:net     A Maelstrom network}"
   [opts]
   {:client    (client (:net opts))
-   :generator (gen/mix [(->> (range) (map (fn [x] {:f :add, :value x})))
-                        (repeat {:f :read})])
+   :generator (mgen/mix [(->> (range) (map (fn [x] {:f :add, :value x})))
+                         (repeat {:f :read})])
    :final-generator (gen/each-thread {:f :read})
    :checker   (checker/set-full)})
@@ -6,7 +6,9 @@
   See also: g-counter, which is identical, but does not allow decrements."
   (:refer-clojure :exclude [read])
   (:require [maelstrom [client :as c]
-                       [net :as net]]
+                       [generator :as mgen]
+                       [net :as net]
+                       [random :as r]]
             [jepsen [checker :as checker]
                     [client :as client]
                     [generator :as gen]]
@@ -131,7 +133,8 @@
       {:net     A Maelstrom network}"
   [opts]
   {:client          (client (:net opts))
-   :generator       (gen/mix [(fn [] {:f :add, :value (- (rand-int 10) 5)})
-                              (repeat {:f :read})])
+   :generator       (mgen/mix [(fn [] {:f :add
+                                          :value (- (r/rand-int 10) 5)})
+                               (repeat {:f :read})])
    :final-generator (gen/each-thread {:f :read, :final? true})
    :checker         (checker)})
@@ -0,0 +1,73 @@
+(ns maelstrom.codec-test
+  (:require [cheshire.core :as json]
+            [clojure.test :refer :all]
+            [maelstrom.codec :refer :all])
+  (:import (java.io ByteArrayInputStream
+                    ByteArrayOutputStream
+                    DataInputStream
+                    DataOutputStream
+                    EOFException)))
+
+(def message
+  {:src  "n1"
+   :dest "c2"
+   :body {:type        :read_ok
+          :in_reply_to 3
+          :value       [1 -2.5 "three" nil true]
+          :tags        #{"a"}
+          :nested      {:x {:y "z"}}}})
+
+(def parsed
+  "What message looks like once decoded: string keys, like parsed JSON."
+  {"src"  "n1"
+   "dest" "c2"
+   "body" {"type"        "read_ok"
+           "in_reply_to" 3
+           "value"       [1 -2.5 "three" nil true]
+           "tags"        ["a"]
+           "nested"      {"x" {"y" "z"}}}})
+
+(defn in
+  "A DataInputStream over the given bytes."
+  [^bytes bs]
+  (DataInputStream. (ByteArrayInputStream. bs)))
+
+(defn frames
+  "Writes each byte array as a frame, and returns the bytes written."
+  [& bss]
+  (let [baos (ByteArrayOutputStream.)
+        out  (DataOutputStream. baos)]
+    (doseq [bs bss]
+      (write-frame! out bs))
+    (.toByteArray baos)))
+
+(deftest round-trip-test
+  (testing "json"
+    (is (= parsed (json/parse-string (json/generate-string message)))))
+
+  (doseq [codec (disj codecs :json)]
+    (testing (name codec)
+      (is (= parsed (decode codec (encode codec message)))))))
+
+(deftest frame-test
+  (let [a  (.getBytes "hello")
+        b  (byte-array 0)
+        bs (frames a b)]
+    (testing "frames"
+      (let [s (in bs)]
+        (is (= (seq a) (seq (read-frame! s))))
+        (is (= [] (vec (read-frame! s))))
+        (testing "clean eof"
+          (is (nil? (read-frame! s))))))
+
+    (testing "eof partway through a frame"
+      (is (thrown-with-msg? EOFException #"partway through a frame of 5 bytes"
+                            (read-frame! (in (byte-array (- (count bs) 6)
+                                                         bs))))))
+
+    (testing "bad length"
+      ; A node printing text instead of frames
+      (is (thrown-with-msg? IllegalStateException #"Invalid frame length"
+                            (read-frame! (in (.getBytes "{\"src\": 1}\n")))))
+      (is (thrown? IllegalStateException
+                   (read-frame! (in (byte-array [-1 -1 -1 -1]))))))))
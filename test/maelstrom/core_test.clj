@@ -2,3 +2,61 @@
   (:require [clojure.test :refer :all]
             [maelstrom.core :refer :all]))
 
+(deftest parse-regions-spec-test
+  (testing "count"
+    (is (= {"n1" "r0", "n2" "r0", "n3" "r1", "n4" "r1"}
+           (parse-regions-spec ["n1" "n2" "n3" "n4"] "2"))))
+
+  (testing "explicit"
+    (is (= {"n1" "east", "n2" "east", "n3" "west"}
+           (parse-regions-spec ["n1" "n2" "n3"] "east=n1,n2; west=n3")))))
+
+(deftest region-latencies-test
+  (let [regions {"n1" "a", "n2" "b", "n3" "c"}
+        inter   {:mean 10, :dist :constant}
+        slow    {:mean 100, :dist :exponential}]
+    (is (= {["a" "b"] inter, ["b" "a"] inter
+            ["a" "c"] slow,  ["c" "a"] slow
+            ["b" "c"] inter, ["c" "b"] inter}
+           (region-latencies regions inter {["a" "c"] slow})))))
+
+(deftest parse-bin-test
+  (is (= "demo/ruby/echo.rb" (parse-bin "demo/ruby/echo.rb")))
+  (is (= {"n1" "v1.rb", "n2" "v2.rb"} (parse-bin "n1=v1.rb,n2=v2.rb")))
+  (is (= {:default "v1.rb", "n3" "v2.rb"} (parse-bin "v1.rb, n3=v2.rb"))))
+
+(deftest check-topology-file-test
+  (let [check (fn [topo]
+                (:errors (check-topology-file
+                           {:options {:nodes         ["n0" "n1" "n2"]
+                                      :topology-file topo}})))]
+    (is (nil? (check nil)))
+    (is (nil? (check {"n0" ["n1"], "n1" ["n0" "n2"], "n2" ["n1"]})))
+    (is (= ["--topology-file mentions nodes which aren't in this test: n5"]
+           (check {"n0" ["n5"]})))
+    (is (= ["--topology-file has links with no link back: n1 -> n2"]
+           (check {"n0" ["n1"], "n1" ["n0" "n2"], "n2" []})))
+    (is (= ["--topology-file doesn't connect every node in this test"]
+           (check {"n0" ["n1"], "n1" ["n0"]})))))
+
+(deftest check-bins-test
+  (let [check (fn [bin nemesis]
+                (:errors (check-bins {:options {:nodes       ["n0" "n1"]
+                                                :bin         bin
+                                                :nemesis     nemesis
+                                                :spare-nodes 2}})))]
+    (is (nil? (check "a.rb" #{:membership})))
+    (is (nil? (check {"n0" "a.rb", "n1" "b.rb"} #{})))
+    (is (= ["--bin doesn't give a binary for nodes: n1"]
+           (check {"n0" "a.rb"} #{})))
+
+    (testing "spare nodes"
+      (is (= ["--bin doesn't give a binary for nodes: n2, n3"]
+             (check {"n0" "a.rb", "n1" "b.rb"} #{:membership})))
+      (is (nil? (check {:default "a.rb", "n1" "b.rb"} #{:membership}))))))
+
+(deftest check-inbox-capacity-test
+  (is (nil? (:errors (check-inbox-capacity {:options {:inbox-capacity 5}}))))
+  (is (= ["--inbox-capacity can't be used with --virtual-time"]
+         (:errors (check-inbox-capacity {:options {:inbox-capacity 5
+                                                   :virtual-time   true}})))))
@@ -0,0 +1,46 @@
+(ns maelstrom.db-test
+  (:require [clojure.test :refer :all]
+            [jepsen.db :as db]
+            [maelstrom [db :refer :all]
+                       [process :as process]]))
+
+(deftest duty-cycle-test
+  (let [signals  (atom [])
+        paused?  (atom false)
+        running? (atom true)]
+    (with-redefs [process/resume-node! (fn [p] (swap! signals conj [:cont p]))
+                  process/pause-node!  (fn [p] (swap! signals conj [:stop p]))]
+      (let [worker (duty-cycle! running? paused?
+                                #(when-not @paused? :p)
+                                1/2 20)]
+        (testing "cycles"
+          (Thread/sleep 100)
+          (is (some #{[:cont :p]} @signals))
+          (is (some #{[:stop :p]} @signals)))
+
+        (testing "leaves paused nodes alone"
+          (locking paused?
+            (reset! paused? true)
+            (reset! signals []))
+          (Thread/sleep 100)
+          (reset! running? false)
+          @worker
+          (is (= [] @signals)))))))
+
+(deftest crash-test
+  (let [calls (atom [])
+        db    (reify db/Process
+                (kill! [_ test node] (swap! calls conj [:kill node]))
+                (start! [_ test node] (swap! calls conj [:start node])))
+        epoch (atom 0)]
+    (testing "restart"
+      (crash! db {} "n1" {:name :c, :restart 10} epoch)
+      (is (= [[:kill "n1"] [:start "n1"]] @calls)))
+
+    (testing "no restart after teardown"
+      (reset! calls [])
+      (let [f (future (crash! db {} "n2" {:name :c, :restart 100} epoch))]
+        (Thread/sleep 20)
+        (swap! epoch inc)
+        @f
+        (is (= [[:kill "n2"]] @calls))))))
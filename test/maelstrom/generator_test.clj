@@ -0,0 +1,55 @@
+(ns maelstrom.generator-test
+  (:require [clojure.test :refer :all]
+            [jepsen.generator :as gen]
+            [maelstrom [generator :refer :all]
+                       [random :as r]]))
+
+(defrecord Ops [f]
+  gen/Generator
+  (op [this test ctx]
+    [{:f f, :time (:time ctx), :value (r/rand-int 1000)} this])
+
+  (update [this test ctx event]
+    this))
+
+(defn ops
+  "Takes the first n ops from a generator, starting each request at the time
+  of the previous op. Asks for each op twice, the way Jepsen might, and
+  checks it gets the same answer."
+  [gen n]
+  (loop [gen gen
+         t   0
+         ops []]
+    (if (= n (count ops))
+      ops
+      (let [[op gen'] (gen/op gen {} {:time t})]
+        (is (= op (first (gen/op gen {} {:time t}))))
+        (recur gen' (:time op) (conj ops op))))))
+
+(defn run
+  "Seeds Maelstrom, and takes n ops from a generator built by (make-gen)."
+  [seed make-gen n]
+  (r/seed! seed)
+  (ops (seeded :generator (make-gen)) n))
+
+(deftest stagger-test
+  (let [make-gen #(stagger 1 (->Ops :x))
+        a        (run 1 make-gen 50)
+        times    (map :time a)]
+    (is (= a (run 1 make-gen 50)))
+    (is (not= a (run 2 make-gen 50)))
+    (is (apply <= times))
+    ; Delays average one second; two seconds at most
+    (is (every? #(< % 2e9) (map - (rest times) times)))
+    (is (< 25e9 (last times) 75e9))))
+
+(deftest mix-test
+  (let [make-gen #(mix [(->Ops :a) (->Ops :b) (->Ops :c)])
+        a        (run 1 make-gen 100)
+        fs       (frequencies (map :f a))]
+    (is (= a (run 1 make-gen 100)))
+    (is (not= a (run 2 make-gen 100)))
+    (is (= #{:a :b :c} (set (keys fs))))
+
+    (testing "exhausted generators drop out"
+      (is (= [:a :a] (map :f (ops (seeded :g (mix [[] (->Ops :a)])) 2)))))))
@@ -0,0 +1,62 @@
+(ns maelstrom.membership-test
+  (:require [clojure.test :refer :all]
+            [jepsen.nemesis :as n]
+            [maelstrom [db :as db]
+                       [membership :refer :all]
+                       [net :as net]]))
+
+(defn fake-db
+  "A Membership which just tracks an atom of a set of members."
+  [members]
+  (reify db/Membership
+    (members [_ test] @members)
+    (join! [_ test node] (swap! members conj node) :joined)
+    (leave! [_ test node] (swap! members disj node) :left)))
+
+(deftest spare-nodes-test
+  (is (= ["n3" "n4"] (spare-nodes ["n0" "n1" "n2"] 2))))
+
+(deftest announce-test
+  (let [net  (net/net {:latency {:mean 0, :dist :constant}})
+        sent (atom [])]
+    (with-redefs [membership! (fn [client node body]
+                                (swap! sent conj [node body]))]
+      (announce! net #{"n1" "n0"} ["n1"] [])
+      (is (= [["n0" {:node_ids ["n0" "n1"], :joined ["n1"], :left []}]
+              ["n1" {:node_ids ["n0" "n1"], :joined ["n1"], :left []}]]
+             @sent))
+      ; The client we announced with is gone again
+      (is (= #{} (set (keys (:queues @net))))))))
+
+(deftest membership-nemesis-test
+  (let [test      {:nodes ["n0" "n1" "n2"]}
+        members   (atom #{"n0" "n1" "n2"})
+        announced (atom [])
+        nemesis   (membership-nemesis (fake-db members) nil ["n3"])
+        invoke!   (fn [f] (:value (n/invoke! nemesis test {:type :info
+                                                           :f    f})))]
+    (with-redefs [announce! (fn [net members joined left]
+                              (swap! announced conj [joined left]))]
+      (testing "leave"
+        (let [{:keys [joined left]} (invoke! :leave)]
+          (is (= [] joined))
+          (is (= 1 (count left)))
+          (is (= 2 (count @members)))))
+
+      (testing "join"
+        (let [{:keys [joined members]} (invoke! :join)]
+          (is (= 1 (count joined)))
+          (is (= 3 (count members)))))
+
+      (testing "restore"
+        (let [{:keys [members]} (invoke! :restore)]
+          (is (every? (set members) (:nodes test)))))
+
+      (testing "the last member never leaves"
+        (reset! members #{"n0"})
+        (is (= {:joined [], :left [], :members ["n0"]} (invoke! :leave))))
+
+      ; Every change was announced, but not the no-op leave.
+      (is (<= 2 (count @announced) 3))
+      (is (every? (fn [[joined left]] (seq (concat joined left)))
+                  @announced)))))
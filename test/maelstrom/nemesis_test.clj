@@ -0,0 +1,57 @@
+(ns maelstrom.nemesis-test
+  (:require [clojure.test :refer :all]
+            [jepsen.nemesis :as n]
+            [maelstrom [db :as mdb]
+                       [nemesis :refer :all]]))
+
+(def nodes ["n1" "n2" "n3" "n4" "n5"])
+
+(defn reachable
+  "Given a grudge, returns the set of nodes which node can hear from."
+  [grudge node]
+  (set (remove (set (get grudge node)) nodes)))
+
+(deftest grudge-test
+  (testing "one"
+    (let [g (grudge nodes :one)]
+      (is (= 1 (count (filter #(= #{%} (reachable g %)) nodes))))))
+
+  (testing "majority"
+    (let [g     (grudge nodes :majority)
+          sizes (sort (map (comp count (partial reachable g)) nodes))]
+      (is (= [2 2 3 3 3] sizes))))
+
+  (testing "one-way"
+    (let [g (grudge nodes :one-way)]
+      ; Two nodes stop hearing from the other three; those three still hear
+      ; from everyone.
+      (is (= 2 (count g)))
+      (is (every? #(= 3 (count %)) (vals g)))
+      (is (every? (fn [loud] (not (contains? g loud)))
+                  (val (first g)))))))
+
+(deftest slow-node-nemesis-test
+  (let [throttled (atom {})
+        db        (reify mdb/Throttle
+                    (throttle! [_ test node duty period]
+                      (swap! throttled assoc node [duty period]))
+                    (unthrottle! [_ test node]
+                      (swap! throttled dissoc node)))
+        nem       (slow-node-nemesis db {:duty 1/4, :period 100})
+        invoke!   (fn [f value]
+                    (:value (n/invoke! nem {} {:type :info, :f f
+                                               :value value})))]
+    (is (= [:slowed ["n1" "n2"] 1/4] (invoke! :start-slow-node ["n1" "n2"])))
+    (is (= {"n1" [1/4 100], "n2" [1/4 100]} @throttled))
+
+    (testing "starting again replaces the slow nodes"
+      (invoke! :start-slow-node ["n3"])
+      (is (= {"n3" [1/4 100]} @throttled)))
+
+    (is (= :stopped (invoke! :stop-slow-node nil)))
+    (is (= {} @throttled))
+
+    (testing "teardown"
+      (invoke! :start-slow-node ["n4"])
+      (n/teardown! nem {})
+      (is (= {} @throttled)))))
@@ -0,0 +1,32 @@
+(ns maelstrom.net.checker-test
+  (:require [clojure.test :refer :all]
+            [maelstrom.net.checker :refer :all]
+            [maelstrom.net.journal :as j]
+            [maelstrom.net.message :as msg]
+            [tesser.core :as t]))
+
+(def regions
+  {"n1" "east", "n2" "east", "n3" "west"})
+
+(def events
+  "A little journal: n1 talks to n2 within east, n1 to n3 across regions, a
+  client talks to n1, and n3 talks to a service."
+  (let [id (atom -1)]
+    (->> [[:send 0 "n1" "n2"] [:recv 0 "n1" "n2"]
+          [:send 1 "n1" "n3"] [:drop 1 "n1" "n3"]
+          [:send 2 "n1" "n3"] [:recv 2 "n1" "n3"]
+          [:send 3 "c1" "n1"] [:recv 3 "c1" "n1"]
+          [:send 4 "n3" "lin-kv"]]
+         (map (fn [[type msg-id src dest]]
+                (j/->Event (swap! id inc) 0 type
+                           (msg/message msg-id src dest {:type "x"})
+                           nil))))))
+
+(deftest region-stats-test
+  (is (= {["east" "east"] {:send-count 1, :recv-count 1, :drop-count 0,
+                           :msg-count 1}
+          ["east" "west"] {:send-count 2, :recv-count 1, :drop-count 1,
+                           :msg-count 2}
+          ["west" nil]    {:send-count 1, :recv-count 0, :drop-count 0,
+                           :msg-count 1}}
+         (t/tesser [events] (region-stats regions)))))
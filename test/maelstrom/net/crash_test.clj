@@ -0,0 +1,21 @@
+(ns maelstrom.net.crash-test
+  (:require [clojure.test :refer :all]
+            [maelstrom.net.crash :refer :all]))
+
+(def msg
+  {:src "n2", :dest "n1", :body {:type "append_entries_ok", :term 3}})
+
+(deftest match-test
+  (testing "direction"
+    (is (match? {:on :send} :send "n2" msg))
+    (is (not (match? {:on :recv} :send "n2" msg))))
+
+  (testing "node and type"
+    (is (match? {:node "n2", :type "append_entries_ok", :on :send}
+                :send "n2" msg))
+    (is (not (match? {:node ["n1" "n3"], :on :send} :send "n2" msg)))
+    (is (not (match? {:type "vote", :on :send} :send "n2" msg))))
+
+  (testing "body fields"
+    (is (match? {:where {:term 3}, :on :send} :send "n2" msg))
+    (is (not (match? {:where {:term 4}, :on :send} :send "n2" msg)))))
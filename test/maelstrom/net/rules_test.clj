@@ -0,0 +1,27 @@
+(ns maelstrom.net.rules-test
+  (:require [clojure.test :refer :all]
+            [maelstrom.net.rules :refer :all]))
+
+(def msg
+  {:src "n1", :dest "n3", :body {:type "append_entries", :msg_id 1}})
+
+(deftest match-test
+  (testing "empty rule matches everything"
+    (is (match? {:action :drop} 0 msg)))
+
+  (testing "single values and collections"
+    (is (match? {:src "n1", :dest ["n2" "n3"], :action :drop} 0 msg))
+    (is (not (match? {:src #{"n2"}, :action :drop} 0 msg)))
+    (is (match? {:type "append_entries", :action :drop} 0 msg))
+    (is (not (match? {:type "vote", :action :drop} 0 msg))))
+
+  (testing "time windows"
+    (let [rule {:after 10, :before 20, :action :drop}]
+      (is (not (match? rule 9.9 msg)))
+      (is (match? rule 10 msg))
+      (is (match? rule 19.9 msg))
+      (is (not (match? rule 20 msg))))))
+
+(deftest tags-test
+  (is (= #{:rule :delay :rule/delay}
+         (tags {:name :delay, :action :delay, :delay 5}))))
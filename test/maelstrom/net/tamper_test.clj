@@ -0,0 +1,39 @@
+(ns maelstrom.net.tamper-test
+  (:require [clojure.test :refer :all]
+            [maelstrom.net.tamper :refer :all]
+            [maelstrom.random :as r]))
+
+(def values
+  [true false 0 1 -1 -0.5 0.0 2.5 "" "x" {} {:a 1} [] [1 2] nil])
+
+(deftest flip-test
+  (doseq [v values]
+    (is (not= v (flip v)) (pr-str v))))
+
+(def bodies
+  [{:type "write", :msg_id 1, :key 3}
+   {:type "cas", :msg_id 2, :key 1, :from 1, :to 1}
+   {:type "broadcast", :message -0.5}
+   {:type "txn", :txn [["r" 1 nil]], :ok true}])
+
+(deftest corrupt-test
+  (r/with-rng (r/rng :corrupt-test)
+    (doseq [body bodies, _ (range 100)]
+      (let [[body' kind] (corrupt body)]
+        (is (#{:flip :drop-key :swap} kind))
+        (is (not= body body') (pr-str body kind))
+        (is (= (select-keys body protected-keys)
+               (select-keys body' protected-keys))))))
+
+  (is (nil? (corrupt {:type "read", :msg_id 1}))))
+
+(deftest equivocate-test
+  (doseq [body bodies]
+    (let [versions (map #(equivocate % body) (range 3))]
+      (testing "each recipient hears a different story"
+        (is (= 3 (count (set (cons body versions))))
+            (pr-str body)))
+      (testing "routing is untouched"
+        (is (every? #(= (select-keys body protected-keys)
+                        (select-keys % protected-keys))
+                    versions))))))
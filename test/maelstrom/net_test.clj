@@ -0,0 +1,277 @@
+(ns maelstrom.net-test
+  (:require [clojure.test :refer :all]
+            [incanter.distributions :as dist]
+            [maelstrom.net :refer :all]
+            [maelstrom.net.journal :as j]
+            [maelstrom.random :as r]
+            [slingshot.slingshot :refer [try+]]))
+
+(defn send-type
+  "Sends a message, returning the :type of whatever it throws, or nil."
+  [net message]
+  (try+ (send! net message)
+        nil
+        (catch map? e
+          (:type e))))
+
+(deftest departed-test
+  (let [net   (net {:latency {:mean 0, :dist :constant}})
+        drops (atom [])
+        msg   (fn [dest] {:src "n0", :dest dest, :body {:type "hi"}})]
+    (with-redefs [j/log-send! (fn [& _])
+                  j/log-drop! (fn [_ message tags]
+                                (swap! drops conj [(:dest message) tags]))]
+      (doto net
+        (add-node! "n0")
+        (add-node! "n1")
+        (depart! "n1")
+        (remove-node! "n1"))
+
+      (testing "messages to a departed node are dropped"
+        (is (= :maelstrom.net/node-not-found (send-type net (msg "n1"))))
+        (is (= [["n1" #{:departed}]] @drops)))
+
+      (testing "messages to a node which never existed are invalid"
+        (is (thrown? AssertionError (send! net (msg "n9")))))
+
+      (testing "a departed node can rejoin"
+        (add-node! net "n1")
+        (is (nil? (send-type net (msg "n1"))))
+        (is (= 1 (.size (queue-for net "n1"))))
+        (is (= 1 (count @drops)))))))
+
+(defn virtual-deliveries
+  "Seeds Maelstrom, sends n messages from n0 to n1 on a virtual-time network,
+  then starts the scheduler, and returns the :i of each message in the order
+  n1 received them."
+  [seed n]
+  (r/seed! seed)
+  (let [net (doto (net {:latency       {:mean 100, :dist :exponential}
+                        :virtual-time? true})
+              (add-node! "n0")
+              (add-node! "n1"))]
+    (doseq [i (range n)]
+      (send! net {:src "n0", :dest "n1", :body {:i i}}))
+    (let [{:keys [running? worker]} (scheduler net)]
+      (try
+        (->> (repeatedly #(recv! net "n1" 1000))
+             (take n)
+             (mapv (comp :i :body)))
+        (finally
+          (reset! running? false)
+          @worker)))))
+
+(deftest scheduler-test
+  (with-redefs [j/log-send! (fn [& _])
+                j/log-recv! (fn [& _])]
+    (testing "delivery order depends only on the seed"
+      (let [a (virtual-deliveries 1 20)]
+        (is (= (range 20) (sort a)))
+        (is (= a (virtual-deliveries 1 20)))
+        (is (not= a (virtual-deliveries 2 20)))))
+
+    (testing "we don't wait for nodes which are down"
+      (let [net (doto (net {:latency       {:mean 10, :dist :constant}
+                            :virtual-time? true})
+                  (add-node! "n0")
+                  (add-node! "n1")
+                  (down! "n1"))
+            {:keys [running? worker]} (scheduler net)
+            t0  (System/nanoTime)]
+        (try
+          (dotimes [i 5]
+            (send! net {:src "n0", :dest "n1", :body {:i i}}))
+          (while (< (.size (queue-for net "n1")) 5)
+            (Thread/sleep 1))
+          (is (< (/ (- (System/nanoTime) t0) 1e6) virtual-max-wait-ms))
+          (is (= 10000000 @(:vclock @net)))
+          (finally
+            (reset! running? false)
+            @worker))))))
+
+(deftest tamper-with-test
+  (let [net {:processes    #{"n1" "n2" "n3"}
+             :p-corrupt    0
+             :equivocators #{"n1"}}
+        msg (fn [src dest] {:src src, :dest dest
+                            :body {:type "vote", :msg_id 1, :term 5}})]
+    (testing "equivocators tell each recipient something different"
+      (let [[m2 tags2] (tamper-with net (msg "n1" "n2"))
+            [m3 tags3] (tamper-with net (msg "n1" "n3"))]
+        (is (= #{:equivocated} tags2 tags3))
+        (is (= 3 (count (distinct [5 (:term (:body m2))
+                                   (:term (:body m3))]))))))
+
+    (testing "honest nodes' messages are left alone"
+      (is (= [(msg "n2" "n3") nil] (tamper-with net (msg "n2" "n3")))))
+
+    (testing "corruption always changes the message"
+      (r/with-rng (r/rng :tamper-with-test)
+        (dotimes [_ 100]
+          (let [m         (msg "n2" "n3")
+                [m' tags] (tamper-with (assoc net :p-corrupt 1) m)]
+            (is (contains? tags :corrupted))
+            (is (not= m m'))))))))
+
+(deftest transmitted-at-test
+  (let [net (fn [scope]
+              {:bandwidth  {:rate 10, :unit :msgs, :scope scope}
+               :processes  #{"n1" "n2" "n3"}
+               :busy-until (atom {})})
+        msg (fn [src dest] {:src src, :dest dest, :body {}})]
+    (testing "node scope"
+      (let [n (net :node)]
+        (is (= 100000000 (transmitted-at n (msg "n1" "n2") 0)))
+        ; Queued behind the first message, even to another node
+        (is (= 200000000 (transmitted-at n (msg "n1" "n3") 0)))
+        ; Other senders aren't held up
+        (is (= 100000000 (transmitted-at n (msg "n2" "n1") 0)))
+        ; Once the sender's idle, we start from now
+        (is (= 600000000 (transmitted-at n (msg "n1" "n2") 500000000)))))
+
+    (testing "link scope"
+      (let [n (net :link)]
+        (is (= 100000000 (transmitted-at n (msg "n1" "n2") 0)))
+        (is (= 100000000 (transmitted-at n (msg "n1" "n3") 0)))
+        (is (= 200000000 (transmitted-at n (msg "n1" "n2") 0)))))
+
+    (testing "clients aren't limited"
+      (is (= 0 (transmitted-at (net :node) (msg "n1" "c1") 0))))))
+
+(deftest inbox-full-test
+  (let [net   (net {:latency        {:mean 0, :dist :constant}
+                    :inbox-capacity 2})
+        drops (atom [])]
+    (with-redefs [j/log-send! (fn [& _])
+                  j/log-recv! (fn [& _])
+                  j/log-drop! (fn [_ message tags]
+                                (swap! drops conj [(:i (:body message)) tags]))]
+      (add-node! net "n0")
+      (add-node! net "n1" {:process? true})
+      (dotimes [i 5]
+        (send! net {:src "n0", :dest "n1", :body {:i i}}))
+      (Thread/sleep 10)
+      (is (= 0 (:i (:body (recv! net "n1" 100)))))
+      ; The oldest message waiting in the inbox is kept; the rest arrived
+      ; when it was full.
+      (is (= [[2 #{:inbox-full}] [3 #{:inbox-full}] [4 #{:inbox-full}]]
+             @drops))
+      (is (= 1 (:i (:body (recv! net "n1" 100)))))
+      (is (nil? (recv! net "n1" 10))))))
+
+(deftest duplicate-test
+  (let [net   (doto (net {:latency     {:mean 0, :dist :constant}
+                          :p-duplicate 1})
+                (add-node! "n0")
+                (add-node! "n1")
+                (add-node! "c0"))
+        recvs (atom [])]
+    (with-redefs [j/log-send! (fn [& _])
+                  j/log-recv! (fn [_ message tags]
+                                (swap! recvs conj [(:i (:body message)) tags]))]
+      (testing "each message between servers arrives twice"
+        (dotimes [i 3]
+          (send! net {:src "n0", :dest "n1", :body {:i i}}))
+        (let [msgs (doall (repeatedly 6 #(recv! net "n1" 100)))]
+          (is (nil? (recv! net "n1" 10)))
+          (is (= {0 2, 1 2, 2 2} (frequencies (map (comp :i :body) msgs))))
+          ; Both copies share a message ID
+          (is (= 3 (count (distinct (map :id msgs)))))
+          ; And the copy is tagged in the journal
+          (is (= {0 [nil #{:duplicate}]
+                  1 [nil #{:duplicate}]
+                  2 [nil #{:duplicate}]}
+                 (->> @recvs
+                      (group-by first)
+                      (map (fn [[i rs]] [i (sort-by some? (map second rs))]))
+                      (into {}))))))
+
+      (testing "messages to clients aren't duplicated"
+        (send! net {:src "n0", :dest "c0", :body {:i 3}})
+        (is (= 3 (:i (:body (recv! net "c0" 100)))))
+        (is (nil? (recv! net "c0" 10)))))))
+
+(defn deliveries
+  "Sends n messages from n0 to n1 with exponentially distributed latency and
+  the given ordering, and returns the :i of each message in the order n1
+  received them."
+  [ordering n]
+  (r/seed! 0)
+  (let [net (doto (net {:latency  {:mean 10, :dist :exponential}
+                        :ordering ordering})
+              (add-node! "n0")
+              (add-node! "n1"))]
+    (dotimes [i n]
+      (send! net {:src "n0", :dest "n1", :body {:i i}}))
+    (->> (repeatedly #(recv! net "n1" 1000))
+         (take n)
+         (mapv (comp :i :body)))))
+
+(deftest fifo-test
+  (with-redefs [j/log-send! (fn [& _])
+                j/log-recv! (fn [& _])]
+    (testing "unordered links reorder messages when latency varies"
+      (is (not= (range 50) (deliveries :unordered 50))))
+
+    (testing "fifo links deliver messages in the order they were sent"
+      (is (= (range 50) (deliveries :fifo 50))))))
+
+(defn draws
+  "Draws n latencies from a distribution, with a fixed seed."
+  [d n]
+  (r/with-rng (r/rng :draws)
+    (vec (repeatedly n #(dist/draw d)))))
+
+(defn mean
+  [xs]
+  (/ (reduce + xs) (count xs)))
+
+(defn median
+  [xs]
+  (nth (sort xs) (quot (count xs) 2)))
+
+(defn about=
+  "Is x within 5% of expected?"
+  [expected x]
+  (< (Math/abs (double (- x expected))) (* 0.05 expected)))
+
+(deftest latency-dist-test
+  (let [n 100000]
+    (testing "lognormal"
+      (let [xs (draws (latency-dist {:dist :lognormal, :mean 100}) n)]
+        (is (every? pos? xs))
+        (is (about= 100 (mean xs)))
+        ; The median is e^mu, or mean / e^(sigma^2/2)
+        (is (about= (/ 100 (Math/exp (/ (Math/pow lognormal-sigma 2) 2)))
+                    (median xs)))))
+
+    (testing "pareto"
+      (let [xs (draws (latency-dist {:dist :pareto, :mean 100}) n)
+            scale (/ (* 100 (dec pareto-shape)) pareto-shape)]
+        ; Nothing is faster than the scale
+        (is (every? #(<= scale %) xs))
+        ; With a shape of 2 the variance is infinite, so the sample mean
+        ; wanders; the median, scale * 2^(1/shape), is steadier.
+        (is (about= (* scale (Math/pow 2 (/ pareto-shape))) (median xs))))
+      ; But with a finite variance, the mean should come out right.
+      (is (about= 100 (mean (draws (pareto-dist 100 3.0) n)))))
+
+    (testing "bimodal"
+      (let [xs   (draws (latency-dist {:dist :bimodal, :mean 100}) n)
+            fast (/ 100 (+ (- 1 bimodal-p-slow)
+                           (* bimodal-p-slow bimodal-slow-factor)))
+            slow (* fast bimodal-slow-factor)]
+        ; Each mode is within +/- 50% of its own mean, rounded down
+        (is (every? #(<= (long (* 0.5 fast)) % (long (* 1.5 slow))) xs))
+        (is (every? #(or (<= % (long (* 1.5 fast)))
+                         (<= (long (* 0.5 slow)) %))
+                    xs))
+        (is (about= bimodal-p-slow
+                    (/ (count (filter #(< (* 1.5 fast) %) xs)) n)))
+        (is (about= 100 (mean xs)))))
+
+    (testing "empirical"
+      (let [trace [1 2 3 10]
+            xs    (draws (latency-dist {:dist :empirical, :trace trace}) n)]
+        (is (= (set trace) (set xs)))
+        (is (about= (mean trace) (mean xs)))))))
@@ -0,0 +1,37 @@
+(ns maelstrom.random-test
+  (:refer-clojure :exclude [rand rand-int rand-nth shuffle])
+  (:require [clojure.test :refer :all]
+            [maelstrom.random :refer :all]))
+
+(defn draws
+  "Draws n ints from the given Random."
+  [rng n]
+  (with-rng rng
+    (vec (repeatedly n #(rand-int 1000000)))))
+
+(deftest rng-test
+  (seed! 1)
+  (let [a (draws (rng "n1") 10)]
+    (testing "a key's stream depends only on the seed and the key"
+      (is (= a (draws (rng "n1") 10)))
+      (is (not= a (draws (rng "n2") 10))))
+
+    (testing "reseeding"
+      (seed! 2)
+      (is (not= a (draws (rng "n1") 10)))
+      (seed! 1)
+      (is (= a (draws (rng "n1") 10))))
+
+    (testing "the root stream"
+      (seed! 1)
+      (let [root (vec (repeatedly 10 #(rand-int 1000000)))]
+        (seed! 1)
+        (is (= root (vec (repeatedly 10 #(rand-int 1000000)))))))))
+
+(deftest helpers-test
+  (seed! 3)
+  (with-rng (rng :test)
+    (is (every? #(<= 0 % 9) (repeatedly 100 #(rand-int 10))))
+    (is (every? #(< 0 % 5) (repeatedly 100 #(+ 0.01 (rand 4.9)))))
+    (is (= (set (range 10)) (set (shuffle (range 10)))))
+    (is (#{:a :b :c} (rand-nth [:a :b :c])))))
@@ -0,0 +1,155 @@
+(ns maelstrom.service-test
+  (:refer-clojure :exclude [peek])
+  (:require [clojure.test :refer :all]
+            [maelstrom.service :refer :all]))
+
+(defn req
+  "Sends a request body from a node to a mutable service, returning the
+  response body."
+  [service node body]
+  (handle! service {:src node, :body body}))
+
+(deftest queue-test
+  (let [q (linearizable (persistent-queue))]
+    (is (= 20 (:code (req q "n1" {:type "dequeue", :key "a"}))))
+    (req q "n1" {:type "enqueue", :key "a", :value 1})
+    (req q "n2" {:type "enqueue", :key "a", :value 2})
+    (is (= {:type "peek_ok", :value 1} (req q "n1" {:type "peek", :key "a"})))
+    (is (= {:type "dequeue_ok", :value 1}
+           (req q "n2" {:type "dequeue", :key "a"})))
+    (is (= {:type "dequeue_ok", :value 2}
+           (req q "n1" {:type "dequeue", :key "a"})))
+    (is (= 20 (:code (req q "n1" {:type "peek", :key "a"}))))))
+
+(deftest lock-test
+  (let [locks (lock-service)
+        t1    (:token (req locks "n1" {:type "acquire", :key "x", :ttl 10000}))]
+    (testing "held"
+      (is (= 11 (:code (req locks "n2" {:type "acquire", :key "x", :ttl 10}))))
+      (is (= 22 (:code (req locks "n2" {:type "release", :key "x"
+                                        :token (dec t1)})))))
+
+    (testing "renew and release"
+      (is (= "renew_ok" (:type (req locks "n1" {:type "renew", :key "x"
+                                                :token t1, :ttl 10000}))))
+      (is (= "release_ok" (:type (req locks "n1" {:type "release", :key "x"
+                                                  :token t1})))))
+
+    (testing "fencing tokens increase"
+      (let [t2 (:token (req locks "n2" {:type "acquire", :key "x", :ttl 0}))]
+        (is (< t1 t2))
+        ; A zero-length lease has already expired
+        (is (= 22 (:code (req locks "n2" {:type "renew", :key "x"
+                                          :token t2, :ttl 10}))))
+        (is (< t2 (:token (req locks "n3" {:type "acquire", :key "x"
+                                           :ttl 10}))))))))
+
+(deftest clock-test
+  (let [now   (atom 1000)
+        clock (clock #(deref now))
+        time  (fn [node] (:time (req clock node {:type "time"})))]
+    (testing "bump"
+      (bump-clock! clock "n1" 500)
+      (is (= 1500 (time "n1")))
+      (is (= 1000 (time "n2")))
+      (bump-clock! clock "n1" -200)
+      (is (= {"n1" 300} (clock-offsets clock))))
+
+    (testing "drift"
+      (drift-clock! clock "n2" 1.5)
+      (is (= 1000 (time "n2")))
+      (reset! now 3000)
+      (is (= 4000 (time "n2")))
+      ; Changing the rate keeps the time gained so far
+      (drift-clock! clock "n2" 0.5)
+      (is (= 4000 (time "n2")))
+      (reset! now 5000)
+      (is (= 5000 (time "n2")))
+      ; Bumps and drift add up
+      (drift-clock! clock "n1" 2)
+      (reset! now 6000)
+      (is (= 7300 (time "n1"))))
+
+    (testing "strobe"
+      (strobe-clock! clock "n3" 100 10 100)
+      (is (= 6005 (do (reset! now 6005) (time "n3"))))
+      (is (= 6115 (do (reset! now 6015) (time "n3"))))
+      (is (= 6025 (do (reset! now 6025) (time "n3"))))
+      (is (= 6195 (do (reset! now 6095) (time "n3"))))
+      ; And once the duration is up, it stops.
+      (is (= 6115 (do (reset! now 6115) (time "n3")))))
+
+    (testing "reset"
+      (doseq [node ["n1" "n2" "n3"]]
+        (reset-clock! clock node)
+        (is (= 6115 (time node))))
+      (is (= {} (clock-offsets clock))))))
+
+(deftest rebase-clock-test
+  (let [clock {:offset 250, :rate 1.1, :since 1000
+               :strobe {:delta 50, :period 20, :since 1000, :until 5000}}]
+    (doseq [now [1000 1010 1020 2345 4990 5000 9999]]
+      (let [clock' (rebase-clock clock now)]
+        (testing (str "at " now)
+          (is (= (skewed-time clock now) (skewed-time clock' now)))
+          (is (= now (:since clock')))
+          (is (= (:strobe clock) (:strobe clock'))))))))
+
+(deftest lock-clock-test
+  (let [now   (atom 1000000)
+        clock (clock #(deref now))
+        locks (lock-service clock)]
+    (bump-clock! clock "n2" 5000)
+
+    (testing "expiry by each node's clock"
+      (is (= 1000100 (:expires_at (req locks "n1" {:type "acquire", :key "x"
+                                                   :ttl 100}))))
+      (swap! now + 200)
+      (is (= 1005300 (:expires_at (req locks "n2" {:type "acquire", :key "x"
+                                                   :ttl 100})))))
+
+    (testing "leases expire by the clock's real time"
+      (is (= 11 (:code (req locks "n1" {:type "acquire", :key "x"
+                                        :ttl 100}))))
+      (swap! now + 100)
+      (is (= 1000400 (:expires_at (req locks "n1" {:type "acquire", :key "x"
+                                                   :ttl 100})))))))
+
+(deftest disk-test
+  (let [d (disk)]
+    (req d "n1" {:type "write", :key "a", :value 1})
+    (req d "n1" {:type "sync"})
+    (req d "n1" {:type "write", :key "a", :value 2})
+    (req d "n1" {:type "write", :key "b", :value 3})
+
+    (testing "each node has its own disk"
+      (is (= 20 (:code (req d "n2" {:type "read", :key "a"})))))
+
+    (testing "unsynced writes are visible"
+      (is (= {:type "read_ok", :value 2}
+             (req d "n1" {:type "read", :key "a"}))))
+
+    (testing "crashes lose unsynced writes"
+      (crash! d "n1")
+      (is (= {:type "read_ok", :value 1}
+             (req d "n1" {:type "read", :key "a"})))
+      (is (= 20 (:code (req d "n1" {:type "read", :key "b"})))))
+
+    (testing "wipes lose everything"
+      (req d "n2" {:type "write", :key "a", :value 4})
+      (wipe! d "n1")
+      (is (= 20 (:code (req d "n1" {:type "read", :key "a"}))))
+      (is (= {:type "read_ok", :value 4}
+             (req d "n2" {:type "read", :key "a"}))))))
+
+(deftest check-request-test
+  (testing "services check their own RPCs"
+    (is (nil? (check-request "disk" {:type "read", :key "a", :msg_id 1})))
+    (is (= 12 (:code (check-request "disk" {:type "read"}))))
+    (is (= 12 (:code (check-request "disk" {:type "read", :key "a"
+                                            :extra true}))))
+    (is (= 12 (:code (check-request "lin-lock" {:type "acquire", :key "a"})))))
+
+  (testing "other services' requests are left alone"
+    (is (nil? (check-request "lin-kv" {:type "read", :key "a", :extra true})))
+    (is (nil? (check-request "disk" {:type "cas", :key "a"})))))
@@ -0,0 +1,36 @@
+(ns maelstrom.workload.broadcast-test
+  (:refer-clojure :exclude [read])
+  (:require [clojure [pprint :refer [pprint]]
+                     [test :refer :all]]
+            [maelstrom.workload.broadcast :refer :all]))
+
+(deftest connected-test
+  (is (connected? {}))
+  (is (connected? {"n0" ["n1"], "n1" ["n0" "n2"], "n2" ["n1"]}))
+  (is (not (connected? {"n0" ["n1"], "n1" ["n0"], "n2" []}))))
+
+(deftest change-topology-test
+  (let [test {:nodes ["n0" "n1" "n2" "n3" "n4"]}]
+    ; A line has no removable links, but it can always grow, or reshape.
+    (loop [i    0
+           topo (line-topology test)]
+      (when (< i 100)
+        (let [[change topo'] (change-topology test topo)]
+          (is (#{:add-link :remove-link :reshape} (first change)))
+          (is (connected? topo'))
+          (is (= (set (:nodes test)) (set (keys topo'))))
+          (recur (inc i) topo'))))))
+
+(deftest topologies-test
+  (let [test {:nodes (mapv (partial str "n") (range 10))}]
+    (doseq [[topo-name topo-fn] topologies]
+      (testing topo-name
+        (let [topo (topo-fn test)]
+          (is (= (set (:nodes test)) (set (keys topo))))
+          (is (connected? topo)))))
+
+    (testing "hypercube"
+      (is (= ["n1" "n2" "n4" "n8"] (get (hypercube-topology test) "n0"))))
+
+    (testing "k-regular"
+      (is (every? #{3} (map count (vals (k-regular-topology 3 test))))))))